@@ -0,0 +1,206 @@
+//! LE advertisements, which can be registered with BlueZ through `LEAdvertisingManager1` so that
+//! it will broadcast them on our behalf.
+
+use crate::generated::OrgBluezLEAdvertisingManager1;
+use dbus::arg::Variant;
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::{MatchRule, MessageType};
+use dbus::nonblock::SyncConnection;
+use dbus::Path;
+use dbus_crossroads::{Crossroads, IfaceToken, MethodErr};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DBUS_METHOD_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+const ADVERTISEMENT_INTERFACE: &str = "org.bluez.LEAdvertisement1";
+
+/// Whether the advertisement is connectable.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AdvertisementType {
+    /// Non-connectable, for broadcasting data.
+    Broadcast,
+    /// Connectable, for advertising a GATT server.
+    Peripheral,
+}
+
+impl AdvertisementType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Broadcast => "broadcast",
+            Self::Peripheral => "peripheral",
+        }
+    }
+}
+
+impl Default for AdvertisementType {
+    fn default() -> Self {
+        Self::Broadcast
+    }
+}
+
+/// The contents of an LE advertisement.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Advertisement {
+    pub advertisement_type: AdvertisementType,
+    /// Service UUIDs to include in the advertisement.
+    pub service_uuids: Vec<String>,
+    /// Manufacturer-specific data, keyed by company ID.
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    /// Service data, keyed by service UUID.
+    pub service_data: HashMap<String, Vec<u8>>,
+    /// The local name to include in the advertisement, if any.
+    pub local_name: Option<String>,
+    /// Whether to include the TX power in the advertisement.
+    pub include_tx_power: bool,
+    /// Whether to advertise as general discoverable. BlueZ picks a default based on the
+    /// advertisement type if this is not set.
+    pub discoverable: Option<bool>,
+}
+
+impl Advertisement {
+    /// Export the advertisement on the given D-Bus connection at `advertisement_path`, and register
+    /// it with the given adapter (e.g. "/org/bluez/hci0") so that it will start being broadcast.
+    ///
+    /// BlueZ reads the contents of the advertisement once when it is registered, so to change it
+    /// you must unregister it and register a new one.
+    pub async fn register(
+        self,
+        connection: Arc<SyncConnection>,
+        adapter_path: &str,
+        advertisement_path: &str,
+    ) -> Result<RegisteredAdvertisement, dbus::Error> {
+        let advertisement_path = Path::new(advertisement_path.to_string()).map_err(|path| {
+            dbus::Error::new_custom(
+                "org.freedesktop.DBus.Error.InvalidArgs",
+                &format!("Invalid object path {:?}", path),
+            )
+        })?;
+        let mut cr = Crossroads::new();
+        let token = register_advertisement_interface(&mut cr);
+        cr.insert(advertisement_path.clone(), &[token], self);
+
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::MethodCall);
+        rule.path = Some(advertisement_path.clone());
+        let token = connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let description = format!("{:?} {:?}", message.path(), message.member());
+                if cr.handle_message(message, connection).is_err() {
+                    log::warn!("Failed to handle D-Bus method call {}", description);
+                }
+                true
+            }),
+        );
+
+        let advertising_manager = dbus::nonblock::Proxy::new(
+            "org.bluez",
+            adapter_path.to_string(),
+            DBUS_METHOD_CALL_TIMEOUT,
+            connection.clone(),
+        );
+        if let Err(e) = advertising_manager
+            .register_advertisement(advertisement_path.clone(), HashMap::new())
+            .await
+        {
+            connection.stop_receive(token);
+            return Err(e);
+        }
+
+        Ok(RegisteredAdvertisement {
+            connection,
+            adapter_path: adapter_path.to_string(),
+            advertisement_path,
+            token,
+        })
+    }
+}
+
+/// An advertisement which has been registered with BlueZ.
+pub struct RegisteredAdvertisement {
+    connection: Arc<SyncConnection>,
+    adapter_path: String,
+    advertisement_path: Path<'static>,
+    token: Token,
+}
+
+impl Debug for RegisteredAdvertisement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredAdvertisement")
+            .field("adapter_path", &self.adapter_path)
+            .field("advertisement_path", &self.advertisement_path)
+            .finish()
+    }
+}
+
+impl RegisteredAdvertisement {
+    /// Stop broadcasting the advertisement, and stop exporting it.
+    pub async fn unregister(self) -> Result<(), dbus::Error> {
+        let advertising_manager = dbus::nonblock::Proxy::new(
+            "org.bluez",
+            self.adapter_path,
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection.clone(),
+        );
+        let result = advertising_manager
+            .unregister_advertisement(self.advertisement_path)
+            .await;
+        self.connection.stop_receive(self.token);
+        result
+    }
+}
+
+fn register_advertisement_interface(cr: &mut Crossroads) -> IfaceToken<Advertisement> {
+    // GetAll leaves out properties whose getter returns an error, which is how we avoid sending
+    // optional properties which haven't been set.
+    cr.register(ADVERTISEMENT_INTERFACE, |b| {
+        // BlueZ calls this when it removes the advertisement, e.g. because the adapter was
+        // powered off. There's nothing for us to clean up.
+        b.method("Release", (), (), |_, _: &mut Advertisement, (): ()| Ok(()));
+        b.property("Type")
+            .get(|_, advertisement: &mut Advertisement| {
+                Ok(advertisement.advertisement_type.as_str().to_string())
+            });
+        b.property("ServiceUUIDs")
+            .get(|_, advertisement: &mut Advertisement| Ok(advertisement.service_uuids.clone()));
+        b.property("ManufacturerData")
+            .get(|_, advertisement: &mut Advertisement| {
+                Ok(advertisement
+                    .manufacturer_data
+                    .iter()
+                    .map(|(id, data)| (*id, Variant(data.clone())))
+                    .collect::<HashMap<_, _>>())
+            });
+        b.property("ServiceData")
+            .get(|_, advertisement: &mut Advertisement| {
+                Ok(advertisement
+                    .service_data
+                    .iter()
+                    .map(|(uuid, data)| (uuid.clone(), Variant(data.clone())))
+                    .collect::<HashMap<_, _>>())
+            });
+        b.property("LocalName")
+            .get(|_, advertisement: &mut Advertisement| {
+                advertisement
+                    .local_name
+                    .clone()
+                    .ok_or_else(|| MethodErr::no_property("LocalName"))
+            });
+        b.property("Includes")
+            .get(|_, advertisement: &mut Advertisement| {
+                Ok(if advertisement.include_tx_power {
+                    vec!["tx-power".to_string()]
+                } else {
+                    vec![]
+                })
+            });
+        b.property("Discoverable")
+            .get(|_, advertisement: &mut Advertisement| {
+                advertisement
+                    .discoverable
+                    .ok_or_else(|| MethodErr::no_property("Discoverable"))
+            });
+    })
+}
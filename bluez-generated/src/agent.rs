@@ -0,0 +1,309 @@
+//! A pairing agent, which can be registered with BlueZ through `AgentManager1` to answer its
+//! questions while pairing with a device.
+
+use crate::generated::OrgBluezAgentManager1;
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::{MatchRule, MessageType};
+use dbus::nonblock::SyncConnection;
+use dbus::Path;
+use dbus_crossroads::{Crossroads, IfaceToken, MethodErr};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DBUS_METHOD_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+const AGENT_INTERFACE: &str = "org.bluez.Agent1";
+
+/// The input and output capabilities of the agent, which determine which pairing method BlueZ
+/// will use.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AgentCapability {
+    DisplayOnly,
+    DisplayYesNo,
+    KeyboardOnly,
+    NoInputNoOutput,
+    KeyboardDisplay,
+}
+
+impl AgentCapability {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::DisplayOnly => "DisplayOnly",
+            Self::DisplayYesNo => "DisplayYesNo",
+            Self::KeyboardOnly => "KeyboardOnly",
+            Self::NoInputNoOutput => "NoInputNoOutput",
+            Self::KeyboardDisplay => "KeyboardDisplay",
+        }
+    }
+}
+
+type ConfirmHandler = Box<dyn Fn(&str, Option<u32>) -> bool + Send + Sync>;
+
+/// A pairing agent.
+///
+/// By default the agent has no passkey or PIN code and no confirmation handler, so it rejects all
+/// requests.
+pub struct Agent {
+    capability: AgentCapability,
+    passkey: Option<u32>,
+    pin_code: Option<String>,
+    confirm_handler: Option<ConfirmHandler>,
+}
+
+impl Debug for Agent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Agent")
+            .field("capability", &self.capability)
+            .field("passkey", &self.passkey)
+            .field("pin_code", &self.pin_code)
+            .field(
+                "confirm_handler",
+                &self.confirm_handler.as_ref().map(|_| "..."),
+            )
+            .finish()
+    }
+}
+
+impl Agent {
+    pub fn new(capability: AgentCapability) -> Self {
+        Agent {
+            capability,
+            passkey: None,
+            pin_code: None,
+            confirm_handler: None,
+        }
+    }
+
+    /// Set a fixed passkey to give when a device asks for one.
+    pub fn with_passkey(mut self, passkey: u32) -> Self {
+        self.passkey = Some(passkey);
+        self
+    }
+
+    /// Set a fixed PIN code to give when a legacy device asks for one.
+    pub fn with_pin_code(mut self, pin_code: &str) -> Self {
+        self.pin_code = Some(pin_code.to_string());
+        self
+    }
+
+    /// Set a function to decide whether to accept a pairing or authorization request. It is
+    /// passed the object path of the device, and the passkey to confirm if there is one. It should
+    /// return true to accept the request, or false to reject it. Without one, all such requests
+    /// are rejected.
+    pub fn on_confirm<F>(mut self, confirm_handler: F) -> Self
+    where
+        F: Fn(&str, Option<u32>) -> bool + Send + Sync + 'static,
+    {
+        self.confirm_handler = Some(Box::new(confirm_handler));
+        self
+    }
+
+    fn request_pin_code(&self) -> Result<String, MethodErr> {
+        self.pin_code.clone().ok_or_else(rejected)
+    }
+
+    fn request_passkey(&self) -> Result<u32, MethodErr> {
+        self.passkey.ok_or_else(rejected)
+    }
+
+    fn confirm(&self, device: &str, passkey: Option<u32>) -> Result<(), MethodErr> {
+        let accepted = match &self.confirm_handler {
+            Some(confirm_handler) => confirm_handler(device, passkey),
+            None => false,
+        };
+        if accepted {
+            Ok(())
+        } else {
+            Err(rejected())
+        }
+    }
+
+    /// Export the agent on the given D-Bus connection at `agent_path`, and register it with BlueZ.
+    /// If `default` is true then it will also be made the default agent, so that it is used for
+    /// pairing requests which weren't started by us.
+    pub async fn register(
+        self,
+        connection: Arc<SyncConnection>,
+        agent_path: &str,
+        default: bool,
+    ) -> Result<RegisteredAgent, dbus::Error> {
+        let agent_path = Path::new(agent_path.to_string()).map_err(|path| {
+            dbus::Error::new_custom(
+                "org.freedesktop.DBus.Error.InvalidArgs",
+                &format!("Invalid object path {:?}", path),
+            )
+        })?;
+        let capability = self.capability;
+        let mut cr = Crossroads::new();
+        let token = register_agent_interface(&mut cr);
+        cr.insert(agent_path.clone(), &[token], self);
+
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::MethodCall);
+        rule.path = Some(agent_path.clone());
+        let token = connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let description = format!("{:?} {:?}", message.path(), message.member());
+                if cr.handle_message(message, connection).is_err() {
+                    log::warn!("Failed to handle D-Bus method call {}", description);
+                }
+                true
+            }),
+        );
+
+        let agent_manager = agent_manager(connection.clone());
+        let mut result = agent_manager
+            .register_agent(agent_path.clone(), capability.as_str())
+            .await;
+        if default && result.is_ok() {
+            result = agent_manager
+                .request_default_agent(agent_path.clone())
+                .await;
+        }
+        if let Err(e) = result {
+            if default {
+                // Registration may have succeeded, in which case BlueZ would otherwise keep trying
+                // to use the agent. If it didn't then this will fail too, which is fine.
+                let _ = agent_manager.unregister_agent(agent_path).await;
+            }
+            connection.stop_receive(token);
+            return Err(e);
+        }
+
+        Ok(RegisteredAgent {
+            connection,
+            agent_path,
+            token,
+        })
+    }
+}
+
+/// An agent which has been registered with BlueZ.
+pub struct RegisteredAgent {
+    connection: Arc<SyncConnection>,
+    agent_path: Path<'static>,
+    token: Token,
+}
+
+impl Debug for RegisteredAgent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredAgent")
+            .field("agent_path", &self.agent_path)
+            .finish()
+    }
+}
+
+impl RegisteredAgent {
+    /// Unregister the agent from BlueZ, and stop exporting it.
+    pub async fn unregister(self) -> Result<(), dbus::Error> {
+        let result = agent_manager(self.connection.clone())
+            .unregister_agent(self.agent_path)
+            .await;
+        self.connection.stop_receive(self.token);
+        result
+    }
+}
+
+fn agent_manager(connection: Arc<SyncConnection>) -> impl OrgBluezAgentManager1 {
+    dbus::nonblock::Proxy::new(
+        "org.bluez",
+        "/org/bluez",
+        DBUS_METHOD_CALL_TIMEOUT,
+        connection,
+    )
+}
+
+fn rejected() -> MethodErr {
+    MethodErr::from(("org.bluez.Error.Rejected", "Rejected by agent"))
+}
+
+fn register_agent_interface(cr: &mut Crossroads) -> IfaceToken<Agent> {
+    cr.register(AGENT_INTERFACE, |b| {
+        b.method("Release", (), (), |_, _: &mut Agent, (): ()| Ok(()));
+        b.method(
+            "RequestPinCode",
+            ("device",),
+            ("pincode",),
+            |_, agent: &mut Agent, (_device,): (Path,)| {
+                agent.request_pin_code().map(|pin_code| (pin_code,))
+            },
+        );
+        b.method(
+            "DisplayPinCode",
+            ("device", "pincode"),
+            (),
+            |_, _: &mut Agent, (_device, _pin_code): (Path, String)| Ok(()),
+        );
+        b.method(
+            "RequestPasskey",
+            ("device",),
+            ("passkey",),
+            |_, agent: &mut Agent, (_device,): (Path,)| {
+                agent.request_passkey().map(|passkey| (passkey,))
+            },
+        );
+        b.method(
+            "DisplayPasskey",
+            ("device", "passkey", "entered"),
+            (),
+            |_, _: &mut Agent, (_device, _passkey, _entered): (Path, u32, u16)| Ok(()),
+        );
+        b.method(
+            "RequestConfirmation",
+            ("device", "passkey"),
+            (),
+            |_, agent: &mut Agent, (device, passkey): (Path, u32)| {
+                agent.confirm(&device, Some(passkey))
+            },
+        );
+        b.method(
+            "RequestAuthorization",
+            ("device",),
+            (),
+            |_, agent: &mut Agent, (device,): (Path,)| agent.confirm(&device, None),
+        );
+        b.method(
+            "AuthorizeService",
+            ("device", "uuid"),
+            (),
+            |_, agent: &mut Agent, (device, _uuid): (Path, String)| agent.confirm(&device, None),
+        );
+        b.method("Cancel", (), (), |_, _: &mut Agent, (): ()| Ok(()));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: &str = "/org/bluez/hci0/dev_11_22_33_44_55_66";
+
+    #[test]
+    fn rejects_everything_by_default() {
+        let agent = Agent::new(AgentCapability::KeyboardDisplay);
+        assert!(agent.request_pin_code().is_err());
+        assert!(agent.request_passkey().is_err());
+        assert!(agent.confirm(DEVICE, Some(123456)).is_err());
+        assert!(agent.confirm(DEVICE, None).is_err());
+    }
+
+    #[test]
+    fn gives_configured_passkey_and_pin_code() {
+        let agent = Agent::new(AgentCapability::KeyboardOnly)
+            .with_passkey(123456)
+            .with_pin_code("0000");
+        assert_eq!(agent.request_passkey().unwrap(), 123456);
+        assert_eq!(agent.request_pin_code().unwrap(), "0000");
+    }
+
+    #[test]
+    fn confirm_handler_decides() {
+        let agent = Agent::new(AgentCapability::DisplayYesNo)
+            .on_confirm(|device, passkey| device == DEVICE && passkey != Some(0));
+        assert!(agent.confirm(DEVICE, Some(123456)).is_ok());
+        assert!(agent.confirm(DEVICE, None).is_ok());
+        assert!(agent.confirm(DEVICE, Some(0)).is_err());
+        assert!(agent.confirm("/org/bluez/hci0/dev_other", None).is_err());
+    }
+}
@@ -55,6 +55,10 @@ pub enum BluetoothEvent {
         object_path: String,
         rssi: i16,
     },
+    BatteryPercentage {
+        object_path: String,
+        percentage: u8,
+    },
     None,
 }
 
@@ -133,9 +137,60 @@ impl BluetoothEvent {
                     }
                 }
 
+                if let Some(value) = properties.get("Percentage") {
+                    if let Some(percentage) = cast::<u8>(&value.0) {
+                        let event = BluetoothEvent::BatteryPercentage {
+                            object_path: object_path.clone(),
+                            percentage: *percentage,
+                        };
+
+                        return Some(event);
+                    }
+                }
+
                 Some(BluetoothEvent::None)
             }
             Err(_err) => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties_changed(
+        object_path: &str,
+        interface: &str,
+        properties: HashMap<String, Variant<Box<dyn RefArg>>>,
+    ) -> Message {
+        Message::signal(
+            &object_path.into(),
+            &"org.freedesktop.DBus.Properties".into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3(interface, properties, Vec::<String>::new())
+    }
+
+    #[test]
+    fn battery_percentage() {
+        let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert("Percentage".to_string(), Variant(Box::new(42u8)));
+        let message = properties_changed(
+            "/org/bluez/hci0/dev_A4_C1_38_12_34_56",
+            "org.bluez.Battery1",
+            properties,
+        );
+
+        match BluetoothEvent::from(message) {
+            Some(BluetoothEvent::BatteryPercentage {
+                object_path,
+                percentage,
+            }) => {
+                assert_eq!(object_path, "/org/bluez/hci0/dev_A4_C1_38_12_34_56");
+                assert_eq!(percentage, 42);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
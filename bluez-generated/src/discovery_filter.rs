@@ -0,0 +1,109 @@
+use dbus::arg::{RefArg, Variant};
+use std::collections::HashMap;
+
+/// The transport to use for discovery.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    /// Interleaved scan, or whatever the adapter supports.
+    Auto,
+    /// BR/EDR inquiry only.
+    BrEdr,
+    /// LE scan only.
+    Le,
+}
+
+impl Transport {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::BrEdr => "bredr",
+            Self::Le => "le",
+        }
+    }
+}
+
+/// A filter to pass to `Adapter1.SetDiscoveryFilter`, so that BlueZ only reports devices we are
+/// interested in.
+///
+/// Fields which are `None` or empty are left out, so BlueZ will use its defaults for them. The
+/// default `DiscoveryFilter` therefore clears any filter which was previously set.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DiscoveryFilter {
+    /// Only report devices which advertise at least one of these service UUIDs.
+    pub service_uuids: Vec<String>,
+    /// Only report devices with an RSSI (in dBm) greater than or equal to this.
+    pub rssi_threshold: Option<i16>,
+    /// Which transport to scan on.
+    pub transport: Option<Transport>,
+    /// Whether to report a property change for every advertisement received, even if the data
+    /// hasn't changed.
+    pub duplicate_data: Option<bool>,
+    /// Only report devices whose address or name starts with this string.
+    pub pattern: Option<String>,
+}
+
+impl DiscoveryFilter {
+    /// Convert the filter to the dictionary expected by the generated `set_discovery_filter`.
+    pub fn to_properties(&self) -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+        let mut properties: HashMap<&'static str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        if !self.service_uuids.is_empty() {
+            properties.insert("UUIDs", Variant(Box::new(self.service_uuids.clone())));
+        }
+        if let Some(rssi_threshold) = self.rssi_threshold {
+            properties.insert("RSSI", Variant(Box::new(rssi_threshold)));
+        }
+        if let Some(transport) = self.transport {
+            properties.insert(
+                "Transport",
+                Variant(Box::new(transport.as_str().to_string())),
+            );
+        }
+        if let Some(duplicate_data) = self.duplicate_data {
+            properties.insert("DuplicateData", Variant(Box::new(duplicate_data)));
+        }
+        if let Some(pattern) = &self.pattern {
+            properties.insert("Pattern", Variant(Box::new(pattern.clone())));
+        }
+        properties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::cast;
+
+    #[test]
+    fn default_filter_is_empty() {
+        assert!(DiscoveryFilter::default().to_properties().is_empty());
+    }
+
+    #[test]
+    fn all_fields_are_converted() {
+        let filter = DiscoveryFilter {
+            service_uuids: vec!["0000fe95-0000-1000-8000-00805f9b34fb".to_string()],
+            rssi_threshold: Some(-80),
+            transport: Some(Transport::Le),
+            duplicate_data: Some(false),
+            pattern: Some("LYWSD".to_string()),
+        };
+
+        let properties = filter.to_properties();
+
+        assert_eq!(properties.len(), 5);
+        assert_eq!(
+            cast::<Vec<String>>(&properties["UUIDs"].0),
+            Some(&filter.service_uuids)
+        );
+        assert_eq!(cast::<i16>(&properties["RSSI"].0), Some(&-80));
+        assert_eq!(
+            cast::<String>(&properties["Transport"].0),
+            Some(&"le".to_string())
+        );
+        assert_eq!(cast::<bool>(&properties["DuplicateData"].0), Some(&false));
+        assert_eq!(
+            cast::<String>(&properties["Pattern"].0),
+            Some(&"LYWSD".to_string())
+        );
+    }
+}
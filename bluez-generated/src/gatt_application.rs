@@ -0,0 +1,632 @@
+//! Server-side GATT objects, which can be registered with BlueZ through `GattManager1` so that
+//! other devices can connect to us and read, write or subscribe to our characteristics.
+
+use crate::generated::OrgBluezGattManager1;
+use dbus::arg::{cast, RefArg, Variant};
+use dbus::channel::{MatchingReceiver, Sender, Token};
+use dbus::message::{MatchRule, MessageType, SignalArgs};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::nonblock::SyncConnection;
+use dbus::Path;
+use dbus_crossroads::{Crossroads, IfaceToken, MethodErr};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DBUS_METHOD_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+const SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+const CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+const DESCRIPTOR_INTERFACE: &str = "org.bluez.GattDescriptor1";
+
+type Options = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// Options passed by BlueZ along with a read or write request.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RequestOptions {
+    /// The offset into the value at which to start reading or writing.
+    pub offset: u16,
+    /// The object path of the remote device which made the request, if known.
+    pub device: Option<String>,
+}
+
+impl RequestOptions {
+    fn from(options: &Options) -> Self {
+        RequestOptions {
+            offset: options
+                .get("offset")
+                .and_then(|offset| cast::<u16>(&offset.0))
+                .copied()
+                .unwrap_or_default(),
+            device: options
+                .get("device")
+                .and_then(|device| cast::<Path>(&device.0))
+                .map(|device| device.to_string()),
+        }
+    }
+}
+
+type ReadHandler = Box<dyn Fn(&RequestOptions) -> Result<Vec<u8>, MethodErr> + Send + Sync>;
+type WriteHandler = Box<dyn Fn(&[u8], &RequestOptions) -> Result<(), MethodErr> + Send + Sync>;
+
+/// A GATT application, made up of one or more services.
+#[derive(Debug)]
+pub struct GattApplication {
+    services: Vec<Service>,
+}
+
+impl GattApplication {
+    pub fn new(services: Vec<Service>) -> Self {
+        GattApplication { services }
+    }
+
+    /// Export the application's objects on the given D-Bus connection under `application_path`,
+    /// and register it with the given adapter (e.g. "/org/bluez/hci0").
+    ///
+    /// The objects stay exported until `RegisteredApplication::unregister` is called.
+    pub async fn register(
+        self,
+        connection: Arc<SyncConnection>,
+        adapter_path: &str,
+        application_path: &str,
+    ) -> Result<RegisteredApplication, dbus::Error> {
+        let application_path = make_path(application_path.to_string())?;
+        let mut cr = Crossroads::new();
+        let service_token = register_service_interface(&mut cr);
+        let characteristic_token = register_characteristic_interface(&mut cr);
+        let descriptor_token = register_descriptor_interface(&mut cr);
+        let object_manager_token = cr.object_manager();
+        cr.insert(application_path.clone(), &[object_manager_token], ());
+
+        for (service_index, service) in self.services.into_iter().enumerate() {
+            let service_path = make_path(format!("{}/service{}", application_path, service_index))?;
+            for (characteristic_index, characteristic) in
+                service.characteristics.into_iter().enumerate()
+            {
+                let characteristic_path =
+                    make_path(format!("{}/char{}", service_path, characteristic_index))?;
+                for (descriptor_index, descriptor) in
+                    characteristic.descriptors.into_iter().enumerate()
+                {
+                    let descriptor_path =
+                        make_path(format!("{}/desc{}", characteristic_path, descriptor_index))?;
+                    cr.insert(
+                        descriptor_path,
+                        &[descriptor_token],
+                        DescriptorObject {
+                            uuid: descriptor.uuid,
+                            flags: descriptor.flags,
+                            characteristic: characteristic_path.clone(),
+                            value: descriptor.value,
+                            read_handler: descriptor.read_handler,
+                            write_handler: descriptor.write_handler,
+                        },
+                    );
+                }
+
+                {
+                    let mut state = characteristic.state.lock().unwrap();
+                    state.emitter = Some((connection.clone(), characteristic_path.clone()));
+                }
+                cr.insert(
+                    characteristic_path,
+                    &[characteristic_token],
+                    CharacteristicObject {
+                        uuid: characteristic.uuid,
+                        flags: characteristic.flags,
+                        service: service_path.clone(),
+                        state: characteristic.state,
+                        read_handler: characteristic.read_handler,
+                        write_handler: characteristic.write_handler,
+                    },
+                );
+            }
+            cr.insert(
+                service_path,
+                &[service_token],
+                ServiceObject {
+                    uuid: service.uuid,
+                    primary: service.primary,
+                },
+            );
+        }
+
+        // Only handle method calls for our own objects, so that other objects can be exported on
+        // the same connection.
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::MethodCall);
+        rule.path = Some(application_path.clone());
+        rule.path_is_namespace = true;
+        let token = connection.start_receive(
+            rule,
+            Box::new(move |message, connection| {
+                let description = format!("{:?} {:?}", message.path(), message.member());
+                if cr.handle_message(message, connection).is_err() {
+                    log::warn!("Failed to handle D-Bus method call {}", description);
+                }
+                true
+            }),
+        );
+
+        let gatt_manager = dbus::nonblock::Proxy::new(
+            "org.bluez",
+            adapter_path.to_string(),
+            DBUS_METHOD_CALL_TIMEOUT,
+            connection.clone(),
+        );
+        if let Err(e) = gatt_manager
+            .register_application(application_path.clone(), HashMap::new())
+            .await
+        {
+            connection.stop_receive(token);
+            return Err(e);
+        }
+
+        Ok(RegisteredApplication {
+            connection,
+            adapter_path: adapter_path.to_string(),
+            application_path,
+            token,
+        })
+    }
+}
+
+/// A GATT application which has been registered with BlueZ.
+pub struct RegisteredApplication {
+    connection: Arc<SyncConnection>,
+    adapter_path: String,
+    application_path: Path<'static>,
+    token: Token,
+}
+
+impl Debug for RegisteredApplication {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredApplication")
+            .field("adapter_path", &self.adapter_path)
+            .field("application_path", &self.application_path)
+            .finish()
+    }
+}
+
+impl RegisteredApplication {
+    /// Unregister the application from BlueZ, and stop exporting its objects.
+    pub async fn unregister(self) -> Result<(), dbus::Error> {
+        let gatt_manager = dbus::nonblock::Proxy::new(
+            "org.bluez",
+            self.adapter_path,
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection.clone(),
+        );
+        let result = gatt_manager
+            .unregister_application(self.application_path)
+            .await;
+        self.connection.stop_receive(self.token);
+        result
+    }
+}
+
+/// A GATT service, made up of one or more characteristics.
+#[derive(Debug)]
+pub struct Service {
+    uuid: String,
+    primary: bool,
+    characteristics: Vec<Characteristic>,
+}
+
+impl Service {
+    pub fn new(uuid: &str, primary: bool, characteristics: Vec<Characteristic>) -> Self {
+        Service {
+            uuid: uuid.to_string(),
+            primary,
+            characteristics,
+        }
+    }
+}
+
+/// A GATT characteristic.
+///
+/// If no read handler is set then reads return the current value, as set by
+/// `CharacteristicHandle::set_value`. Writes update the current value from their offset onwards,
+/// after being passed to the write handler if there is one.
+pub struct Characteristic {
+    uuid: String,
+    flags: Vec<String>,
+    state: Arc<Mutex<CharacteristicState>>,
+    read_handler: Option<ReadHandler>,
+    write_handler: Option<WriteHandler>,
+    descriptors: Vec<Descriptor>,
+}
+
+impl Debug for Characteristic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Characteristic")
+            .field("uuid", &self.uuid)
+            .field("flags", &self.flags)
+            .field("state", &self.state)
+            .field("read_handler", &self.read_handler.as_ref().map(|_| "..."))
+            .field("write_handler", &self.write_handler.as_ref().map(|_| "..."))
+            .field("descriptors", &self.descriptors)
+            .finish()
+    }
+}
+
+impl Characteristic {
+    /// Create a new characteristic with the given UUID and
+    /// [flags](https://git.kernel.org/pub/scm/bluetooth/bluez.git/tree/doc/gatt-api.txt), such as
+    /// "read", "write" or "notify".
+    pub fn new(uuid: &str, flags: &[&str]) -> Self {
+        Characteristic {
+            uuid: uuid.to_string(),
+            flags: flags.iter().map(|flag| flag.to_string()).collect(),
+            state: Default::default(),
+            read_handler: None,
+            write_handler: None,
+            descriptors: vec![],
+        }
+    }
+
+    /// Set a function to be called to get the value when a remote device reads the
+    /// characteristic.
+    pub fn on_read<F>(mut self, read_handler: F) -> Self
+    where
+        F: Fn(&RequestOptions) -> Result<Vec<u8>, MethodErr> + Send + Sync + 'static,
+    {
+        self.read_handler = Some(Box::new(read_handler));
+        self
+    }
+
+    /// Set a function to be called when a remote device writes to the characteristic. If it
+    /// returns an error then the write is rejected.
+    pub fn on_write<F>(mut self, write_handler: F) -> Self
+    where
+        F: Fn(&[u8], &RequestOptions) -> Result<(), MethodErr> + Send + Sync + 'static,
+    {
+        self.write_handler = Some(Box::new(write_handler));
+        self
+    }
+
+    pub fn with_descriptor(mut self, descriptor: Descriptor) -> Self {
+        self.descriptors.push(descriptor);
+        self
+    }
+
+    /// Get a handle which can be used to update the value of the characteristic, and notify
+    /// subscribed devices, after the application has been registered.
+    pub fn handle(&self) -> CharacteristicHandle {
+        CharacteristicHandle {
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct CharacteristicState {
+    value: Vec<u8>,
+    notifying: bool,
+    emitter: Option<(Arc<SyncConnection>, Path<'static>)>,
+}
+
+impl Debug for CharacteristicState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CharacteristicState")
+            .field("value", &self.value)
+            .field("notifying", &self.notifying)
+            .field("path", &self.emitter.as_ref().map(|(_, path)| path))
+            .finish()
+    }
+}
+
+/// A handle to update the value of a characteristic.
+#[derive(Clone, Debug)]
+pub struct CharacteristicHandle {
+    state: Arc<Mutex<CharacteristicState>>,
+}
+
+impl CharacteristicHandle {
+    /// Set the value of the characteristic. If a remote device has subscribed to notifications
+    /// then it will be notified of the new value.
+    pub fn set_value(&self, value: Vec<u8>) -> Result<(), dbus::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.value = value;
+        if !state.notifying {
+            return Ok(());
+        }
+        if let Some((connection, path)) = &state.emitter {
+            let mut changed_properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+            changed_properties.insert("Value".to_string(), Variant(Box::new(state.value.clone())));
+            let message = PropertiesPropertiesChanged {
+                interface_name: CHARACTERISTIC_INTERFACE.to_string(),
+                changed_properties,
+                invalidated_properties: vec![],
+            }
+            .to_emit_message(path);
+            connection.send(message).map_err(|()| {
+                dbus::Error::new_custom(
+                    "org.freedesktop.DBus.Error.Failed",
+                    "Failed to send notification",
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether a remote device has currently subscribed to notifications.
+    pub fn is_notifying(&self) -> bool {
+        self.state.lock().unwrap().notifying
+    }
+}
+
+/// A GATT descriptor of a characteristic.
+pub struct Descriptor {
+    uuid: String,
+    flags: Vec<String>,
+    value: Vec<u8>,
+    read_handler: Option<ReadHandler>,
+    write_handler: Option<WriteHandler>,
+}
+
+impl Debug for Descriptor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Descriptor")
+            .field("uuid", &self.uuid)
+            .field("flags", &self.flags)
+            .field("value", &self.value)
+            .field("read_handler", &self.read_handler.as_ref().map(|_| "..."))
+            .field("write_handler", &self.write_handler.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl Descriptor {
+    /// Create a new descriptor with the given UUID, flags and initial value.
+    pub fn new(uuid: &str, flags: &[&str], value: Vec<u8>) -> Self {
+        Descriptor {
+            uuid: uuid.to_string(),
+            flags: flags.iter().map(|flag| flag.to_string()).collect(),
+            value,
+            read_handler: None,
+            write_handler: None,
+        }
+    }
+
+    /// Set a function to be called to get the value when a remote device reads the descriptor.
+    pub fn on_read<F>(mut self, read_handler: F) -> Self
+    where
+        F: Fn(&RequestOptions) -> Result<Vec<u8>, MethodErr> + Send + Sync + 'static,
+    {
+        self.read_handler = Some(Box::new(read_handler));
+        self
+    }
+
+    /// Set a function to be called when a remote device writes to the descriptor. If it returns
+    /// an error then the write is rejected.
+    pub fn on_write<F>(mut self, write_handler: F) -> Self
+    where
+        F: Fn(&[u8], &RequestOptions) -> Result<(), MethodErr> + Send + Sync + 'static,
+    {
+        self.write_handler = Some(Box::new(write_handler));
+        self
+    }
+}
+
+struct ServiceObject {
+    uuid: String,
+    primary: bool,
+}
+
+struct CharacteristicObject {
+    uuid: String,
+    flags: Vec<String>,
+    service: Path<'static>,
+    state: Arc<Mutex<CharacteristicState>>,
+    read_handler: Option<ReadHandler>,
+    write_handler: Option<WriteHandler>,
+}
+
+struct DescriptorObject {
+    uuid: String,
+    flags: Vec<String>,
+    characteristic: Path<'static>,
+    value: Vec<u8>,
+    read_handler: Option<ReadHandler>,
+    write_handler: Option<WriteHandler>,
+}
+
+fn register_service_interface(cr: &mut Crossroads) -> IfaceToken<ServiceObject> {
+    cr.register(SERVICE_INTERFACE, |b| {
+        b.property("UUID")
+            .get(|_, service: &mut ServiceObject| Ok(service.uuid.clone()));
+        b.property("Primary")
+            .get(|_, service: &mut ServiceObject| Ok(service.primary));
+    })
+}
+
+fn register_characteristic_interface(cr: &mut Crossroads) -> IfaceToken<CharacteristicObject> {
+    cr.register(CHARACTERISTIC_INTERFACE, |b| {
+        b.method(
+            "ReadValue",
+            ("options",),
+            ("value",),
+            |_, characteristic: &mut CharacteristicObject, (options,): (Options,)| {
+                let options = RequestOptions::from(&options);
+                let value = match &characteristic.read_handler {
+                    Some(read_handler) => read_handler(&options)?,
+                    None => characteristic.state.lock().unwrap().value.clone(),
+                };
+                Ok((value_from_offset(value, options.offset)?,))
+            },
+        );
+        b.method(
+            "WriteValue",
+            ("value", "options"),
+            (),
+            |_, characteristic: &mut CharacteristicObject, (value, options): (Vec<u8>, Options)| {
+                let options = RequestOptions::from(&options);
+                check_offset(&characteristic.state.lock().unwrap().value, options.offset)?;
+                if let Some(write_handler) = &characteristic.write_handler {
+                    write_handler(&value, &options)?;
+                }
+                let mut state = characteristic.state.lock().unwrap();
+                write_at_offset(&mut state.value, &value, options.offset)
+            },
+        );
+        b.method(
+            "StartNotify",
+            (),
+            (),
+            |_, characteristic: &mut CharacteristicObject, (): ()| {
+                check_can_notify(characteristic)?;
+                characteristic.state.lock().unwrap().notifying = true;
+                Ok(())
+            },
+        );
+        b.method(
+            "StopNotify",
+            (),
+            (),
+            |_, characteristic: &mut CharacteristicObject, (): ()| {
+                check_can_notify(characteristic)?;
+                characteristic.state.lock().unwrap().notifying = false;
+                Ok(())
+            },
+        );
+        b.property("UUID")
+            .get(|_, characteristic: &mut CharacteristicObject| Ok(characteristic.uuid.clone()));
+        b.property("Service")
+            .get(|_, characteristic: &mut CharacteristicObject| Ok(characteristic.service.clone()));
+        b.property("Flags")
+            .get(|_, characteristic: &mut CharacteristicObject| Ok(characteristic.flags.clone()));
+        b.property("Notifying")
+            .get(|_, characteristic: &mut CharacteristicObject| {
+                Ok(characteristic.state.lock().unwrap().notifying)
+            });
+    })
+}
+
+fn register_descriptor_interface(cr: &mut Crossroads) -> IfaceToken<DescriptorObject> {
+    cr.register(DESCRIPTOR_INTERFACE, |b| {
+        b.method(
+            "ReadValue",
+            ("options",),
+            ("value",),
+            |_, descriptor: &mut DescriptorObject, (options,): (Options,)| {
+                let options = RequestOptions::from(&options);
+                let value = match &descriptor.read_handler {
+                    Some(read_handler) => read_handler(&options)?,
+                    None => descriptor.value.clone(),
+                };
+                Ok((value_from_offset(value, options.offset)?,))
+            },
+        );
+        b.method(
+            "WriteValue",
+            ("value", "options"),
+            (),
+            |_, descriptor: &mut DescriptorObject, (value, options): (Vec<u8>, Options)| {
+                let options = RequestOptions::from(&options);
+                check_offset(&descriptor.value, options.offset)?;
+                if let Some(write_handler) = &descriptor.write_handler {
+                    write_handler(&value, &options)?;
+                }
+                write_at_offset(&mut descriptor.value, &value, options.offset)
+            },
+        );
+        b.property("UUID")
+            .get(|_, descriptor: &mut DescriptorObject| Ok(descriptor.uuid.clone()));
+        b.property("Characteristic")
+            .get(|_, descriptor: &mut DescriptorObject| Ok(descriptor.characteristic.clone()));
+        b.property("Flags")
+            .get(|_, descriptor: &mut DescriptorObject| Ok(descriptor.flags.clone()));
+    })
+}
+
+fn check_can_notify(characteristic: &CharacteristicObject) -> Result<(), MethodErr> {
+    if characteristic
+        .flags
+        .iter()
+        .any(|flag| flag == "notify" || flag == "indicate")
+    {
+        Ok(())
+    } else {
+        Err(MethodErr::from((
+            "org.bluez.Error.NotSupported",
+            "Characteristic doesn't support notifications",
+        )))
+    }
+}
+
+fn value_from_offset(mut value: Vec<u8>, offset: u16) -> Result<Vec<u8>, MethodErr> {
+    check_offset(&value, offset)?;
+    Ok(value.split_off(offset as usize))
+}
+
+/// Replace the value from the given offset onwards with the given data, as for one part of a long
+/// write.
+fn write_at_offset(value: &mut Vec<u8>, data: &[u8], offset: u16) -> Result<(), MethodErr> {
+    check_offset(value, offset)?;
+    value.splice(offset as usize.., data.iter().copied());
+    Ok(())
+}
+
+fn check_offset(value: &[u8], offset: u16) -> Result<(), MethodErr> {
+    if offset as usize > value.len() {
+        Err(MethodErr::from((
+            "org.bluez.Error.InvalidOffset",
+            "Offset is past the end of the value",
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn make_path(path: String) -> Result<Path<'static>, dbus::Error> {
+    Path::new(path).map_err(|path| {
+        dbus::Error::new_custom(
+            "org.freedesktop.DBus.Error.InvalidArgs",
+            &format!("Invalid object path {:?}", path),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_from_offset_skips_start() {
+        assert_eq!(value_from_offset(vec![1, 2, 3], 0).unwrap(), vec![1, 2, 3]);
+        assert_eq!(value_from_offset(vec![1, 2, 3], 2).unwrap(), vec![3]);
+        assert_eq!(value_from_offset(vec![1, 2, 3], 3).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn value_from_offset_fails_past_end() {
+        assert!(value_from_offset(vec![1, 2, 3], 4).is_err());
+    }
+
+    #[test]
+    fn write_at_offset_replaces_end() {
+        let mut value = vec![1, 2, 3];
+        write_at_offset(&mut value, &[4, 5], 0).unwrap();
+        assert_eq!(value, vec![4, 5]);
+        write_at_offset(&mut value, &[6, 7], 1).unwrap();
+        assert_eq!(value, vec![4, 6, 7]);
+        write_at_offset(&mut value, &[8], 3).unwrap();
+        assert_eq!(value, vec![4, 6, 7, 8]);
+    }
+
+    #[test]
+    fn write_at_offset_fails_past_end() {
+        let mut value = vec![1, 2, 3];
+        assert!(write_at_offset(&mut value, &[4], 4).is_err());
+        assert_eq!(value, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn request_options_defaults() {
+        assert_eq!(
+            RequestOptions::from(&HashMap::new()),
+            RequestOptions::default()
+        );
+    }
+}
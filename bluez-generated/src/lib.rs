@@ -1,2 +1,6 @@
+pub mod advertisement;
+pub mod agent;
 pub mod bluetooth_event;
+pub mod discovery_filter;
+pub mod gatt_application;
 pub mod generated;
@@ -0,0 +1,58 @@
+use futures::FutureExt;
+use homie::controller::{Event, HomieController};
+use rumqttc::MqttOptions;
+use std::error::Error;
+use tokio::task::{self, JoinHandle};
+use tokio::try_join;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    pretty_env_logger::init();
+
+    let mqttoptions = MqttOptions::new("homie_controller", "test.mosquitto.org", 1883);
+
+    let (controller, events, controller_handle) =
+        HomieController::spawn("homie", mqttoptions).await?;
+
+    let handle: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> = task::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            match event {
+                Event::DeviceDiscovered { device_id } => println!("Found {}", device_id),
+                Event::DeviceRemoved { device_id } => println!("Lost {}", device_id),
+                Event::PropertyValueChanged {
+                    device_id,
+                    node_id,
+                    index,
+                    property_id,
+                    payload,
+                } => {
+                    let devices = controller.devices();
+                    let unit = devices
+                        .get(&device_id)
+                        .and_then(|device| device.nodes.get(&node_id))
+                        .and_then(|node| node.properties.get(&property_id))
+                        .and_then(|property| property.unit.clone())
+                        .unwrap_or_default();
+                    let node_id = match index {
+                        Some(index) => format!("{}[{}]", node_id, index),
+                        None => node_id,
+                    };
+                    println!(
+                        "{}/{}/{} = {}{}",
+                        device_id, node_id, property_id, payload, unit
+                    );
+                }
+                Event::DeviceUpdated { .. } => {}
+            }
+        }
+        Ok(())
+    });
+
+    // Poll everything to completion, until the first one bombs out.
+    let res: Result<_, Box<dyn Error + Send + Sync>> = try_join! {
+        controller_handle,
+        handle.map(|res| Ok(res??)),
+    };
+    res?;
+    Ok(())
+}
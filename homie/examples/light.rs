@@ -8,27 +8,35 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let mqttoptions = MqttOptions::new("homie_example", "test.mosquitto.org", 1883);
 
-    let mut builder = HomieDevice::builder("homie/example", "Homie light example", mqttoptions);
-    builder.set_update_callback(|node_id, property_id, value| async move {
-        println!("{}/{} is now {}", node_id, property_id, value);
-        Some(value)
-    });
-    let (mut homie, homie_handle) = builder.spawn().await?;
+    let (mut homie, homie_handle) =
+        HomieDevice::builder("homie/example", "Homie light example", mqttoptions)
+            .spawn()
+            .await?;
 
     let node = Node::new(
         "light",
         "Light",
         "light",
         vec![
-            Property::new("power", "On", Datatype::Boolean, true, None, None),
-            Property::new("colour", "Colour", Datatype::Color, true, None, Some("rgb")),
+            Property::new("power", "On", Datatype::Boolean, true, None, None)?.with_set_handler(
+                |value| async move {
+                    println!("power is now {}", value);
+                    Ok(Some(value))
+                },
+            ),
+            Property::new("colour", "Colour", Datatype::Color, true, None, Some("rgb"))?
+                .with_set_handler(|value| async move {
+                    println!("colour is now {}", value);
+                    Ok(Some(value))
+                }),
         ],
-    );
+    )?;
     homie.add_node(node).await?;
 
     homie.ready().await?;
     println!("Ready");
 
-    // This will only resolve (with an error) if we lose connection to the MQTT server.
+    // This will only resolve (with an error) if we can't connect to the MQTT server in the first
+    // place. Once connected, it will keep trying to reconnect if the connection is lost.
     homie_handle.await
 }
@@ -31,7 +31,7 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
                     false,
                     Some("ºC"),
                     None,
-                ),
+                )?,
                 Property::new(
                     "humidity",
                     "Humidity",
@@ -39,9 +39,9 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
                     false,
                     Some("%"),
                     None,
-                ),
+                )?,
             ],
-        ))
+        )?)
         .await?;
 
     let handle: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> = task::spawn(async move {
@@ -0,0 +1,760 @@
+//! A Homie [controller](https://homieiot.github.io/specification/#controller-role), which
+//! discovers Homie devices on an MQTT broker, keeps track of their nodes, properties and values,
+//! and can set their settable properties.
+//!
+//! Only devices using Homie 3 or 4 are discovered. Homie 5 devices are published under a different
+//! topic layout and describe themselves with a single `$description` document, which isn't
+//! supported yet.
+
+use crate::{
+    try_join_unit_handles, Datatype, Error, State, Value, RECONNECT_INTERVAL, REQUESTS_CAP,
+};
+use async_channel::{Receiver, SendError, Sender};
+use rumqttc::{EventLoop, Incoming, MqttOptions, Publish, QoS, Request, Subscribe};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::str;
+use std::sync::{Arc, Mutex};
+use tokio::task::{self, JoinHandle};
+use tokio::time::delay_for;
+
+/// A Homie device which has been discovered by the controller.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Device {
+    pub id: String,
+    /// The version of the Homie convention which the device implements.
+    pub homie_version: String,
+    pub name: Option<String>,
+    pub state: Option<State>,
+    pub implementation: Option<String>,
+    pub extensions: Vec<String>,
+    pub nodes: HashMap<String, Node>,
+}
+
+/// A node of a Homie device which has been discovered by the controller.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Node {
+    pub id: String,
+    pub name: Option<String>,
+    pub node_type: Option<String>,
+    pub properties: HashMap<String, Property>,
+    /// The range of indices of the node's elements, if it is an array.
+    pub array: Option<RangeInclusive<u32>>,
+    /// The elements of the node by index, if it is an array.
+    pub elements: BTreeMap<u32, NodeElement>,
+}
+
+impl Node {
+    /// Parse the last value published for the given property of the element with the given index,
+    /// if the node is an array.
+    pub fn element_value(&self, index: u32, property_id: &str) -> Option<Value> {
+        let property = self.properties.get(property_id)?;
+        let payload = self.elements.get(&index)?.payloads.get(property_id)?;
+        Value::parse(payload, property.datatype?, property.format.as_deref()).ok()
+    }
+}
+
+/// An element of a node array which has been discovered by the controller. Its properties are
+/// those of the node.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NodeElement {
+    pub name: Option<String>,
+    /// The last payload published for each property of the element, by property ID.
+    pub payloads: HashMap<String, String>,
+}
+
+/// A property of a node of a Homie device which has been discovered by the controller.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Property {
+    pub id: String,
+    pub name: Option<String>,
+    pub datatype: Option<Datatype>,
+    pub settable: bool,
+    pub unit: Option<String>,
+    pub format: Option<String>,
+    /// The last payload published for the property's value, if any.
+    pub payload: Option<String>,
+}
+
+impl Property {
+    /// Parse the last value published for the property, if it is valid for the property's datatype
+    /// and format.
+    pub fn value(&self) -> Option<Value> {
+        Value::parse(
+            self.payload.as_ref()?,
+            self.datatype?,
+            self.format.as_deref(),
+        )
+        .ok()
+    }
+}
+
+/// A change to a Homie device which the controller has seen.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// A new device has been discovered.
+    DeviceDiscovered { device_id: String },
+    /// One of the attributes of a device, or its nodes or properties, has changed.
+    DeviceUpdated { device_id: String },
+    /// A device has been removed from the broker.
+    DeviceRemoved { device_id: String },
+    /// A new value has been published for a property.
+    PropertyValueChanged {
+        device_id: String,
+        node_id: String,
+        /// The index of the element, if the node is an array.
+        index: Option<u32>,
+        property_id: String,
+        payload: String,
+    },
+}
+
+/// A Homie controller, which watches all the Homie devices under a base topic.
+#[derive(Clone, Debug)]
+pub struct HomieController {
+    requests_tx: Sender<Request>,
+    base_topic: String,
+    devices: Arc<Mutex<HashMap<String, Device>>>,
+    subscriptions: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl HomieController {
+    /// Connect to the MQTT broker, and start a task to discover and watch Homie 3 and 4 devices.
+    ///
+    /// # Arguments
+    /// * `base_topic`: The Homie [base topic](https://homieiot.github.io/specification/#base-topic)
+    ///   to look for devices under, usually "homie".
+    /// * `mqtt_options`: Options for the MQTT connection, including which server to connect to.
+    ///
+    /// # Return value
+    /// The `HomieController` itself, a channel of events about changes to devices, and a `Future`
+    /// for the tasks which handle the MQTT connection. You should join on this future to handle any
+    /// errors it returns.
+    pub async fn spawn(
+        base_topic: &str,
+        mqtt_options: MqttOptions,
+    ) -> Result<
+        (
+            HomieController,
+            Receiver<Event>,
+            impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>>,
+        ),
+        SendError<Request>,
+    > {
+        let event_loop = EventLoop::new(mqtt_options, REQUESTS_CAP);
+        let controller = HomieController {
+            requests_tx: event_loop.handle(),
+            base_topic: base_topic.to_owned(),
+            devices: Default::default(),
+            subscriptions: Default::default(),
+        };
+        let (events_tx, events_rx) = async_channel::unbounded();
+        let handle = controller.spawn_tasks(event_loop, events_tx);
+        controller
+            .subscribe(&format!("{}/+/$homie", controller.base_topic))
+            .await?;
+        Ok((controller, events_rx, handle))
+    }
+
+    /// Get a snapshot of all the devices which have been discovered so far, keyed by device ID.
+    pub fn devices(&self) -> HashMap<String, Device> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// Set the given property of the given device to a new value. The node ID may be the topic ID
+    /// of an element of a node array, like "sensors_1".
+    ///
+    /// This will return an error without sending anything if the property isn't known to be
+    /// settable, or the value doesn't match the property's datatype and format.
+    pub async fn set(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        value: impl Into<Value>,
+    ) -> Result<(), Error> {
+        let value = value.into();
+        {
+            let devices = self.devices.lock().unwrap();
+            let device = devices.get(device_id).ok_or_else(|| Error::UnknownDevice {
+                device_id: device_id.to_owned(),
+            })?;
+            // The properties of the elements of a node array are described by the node.
+            let node = match find_element(device, node_id) {
+                Some((array_node_id, _)) => device.nodes.get(&array_node_id),
+                None => device.nodes.get(node_id),
+            };
+            let property = node
+                .and_then(|node| node.properties.get(property_id))
+                .ok_or_else(|| Error::UnknownProperty {
+                    node_id: node_id.to_owned(),
+                    property_id: property_id.to_owned(),
+                })?;
+            if !property.settable {
+                return Err(Error::NotSettable {
+                    node_id: node_id.to_owned(),
+                    property_id: property_id.to_owned(),
+                });
+            }
+            if let Some(datatype) = property.datatype {
+                value
+                    .validate(datatype, property.format.as_deref())
+                    .map_err(|reason| Error::InvalidValue {
+                        node_id: node_id.to_owned(),
+                        property_id: property_id.to_owned(),
+                        reason,
+                    })?;
+            }
+        }
+        let topic = format!(
+            "{}/{}/{}/{}/set",
+            self.base_topic, device_id, node_id, property_id
+        );
+        let publish = Publish::new(topic, QoS::AtLeastOnce, value.to_string());
+        self.requests_tx.send(publish.into()).await?;
+        Ok(())
+    }
+
+    /// Set the given property of the element with the given index of the given node array to a new
+    /// value.
+    ///
+    /// This will return an error without sending anything if the node isn't known to be an array
+    /// with an element at the index, or for any of the reasons that `set` would.
+    pub async fn set_element(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        index: u32,
+        property_id: &str,
+        value: impl Into<Value>,
+    ) -> Result<(), Error> {
+        {
+            let devices = self.devices.lock().unwrap();
+            let device = devices.get(device_id).ok_or_else(|| Error::UnknownDevice {
+                device_id: device_id.to_owned(),
+            })?;
+            let node = device
+                .nodes
+                .get(node_id)
+                .ok_or_else(|| Error::UnknownNode {
+                    node_id: node_id.to_owned(),
+                })?;
+            if !matches!(&node.array, Some(array) if array.contains(&index)) {
+                return Err(Error::InvalidArrayIndex {
+                    node_id: node_id.to_owned(),
+                    index,
+                });
+            }
+        }
+        let element_id = format!("{}_{}", node_id, index);
+        self.set(device_id, &element_id, property_id, value).await
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<(), SendError<Request>> {
+        self.subscriptions.lock().unwrap().insert(topic.to_owned());
+        let subscribe = Subscribe::new(topic, QoS::AtLeastOnce);
+        self.requests_tx.send(subscribe.into()).await
+    }
+
+    /// Spawn tasks to handle the EventLoop and the messages it receives.
+    fn spawn_tasks(
+        &self,
+        mut event_loop: EventLoop,
+        events_tx: Sender<Event>,
+    ) -> impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>> {
+        let (incoming_tx, incoming_rx) = async_channel::unbounded();
+
+        let mqtt_task: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
+            task::spawn(async move {
+                loop {
+                    match event_loop.poll().await {
+                        Ok((incoming, outgoing)) => {
+                            log::trace!("Incoming = {:?}, Outgoing = {:?}", incoming, outgoing);
+
+                            if let Some(incoming) = incoming {
+                                incoming_tx.send(incoming).await?;
+                            }
+                        }
+                        Err(e) => {
+                            log::error!(
+                                "MQTT connection failed, reconnecting in {:?}: {}",
+                                RECONNECT_INTERVAL,
+                                e
+                            );
+                            delay_for(RECONNECT_INTERVAL).await;
+                        }
+                    }
+                }
+            });
+
+        let controller = self.clone();
+        let device_prefix = format!("{}/", self.base_topic);
+        let incoming_task: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
+            task::spawn(async move {
+                let mut connected_before = false;
+                while let Ok(incoming) = incoming_rx.recv().await {
+                    match incoming {
+                        Incoming::ConnAck(_) => {
+                            // The broker may have forgotten our subscriptions.
+                            if connected_before {
+                                let subscriptions =
+                                    controller.subscriptions.lock().unwrap().clone();
+                                for topic in subscriptions {
+                                    controller.subscribe(&topic).await?;
+                                }
+                            }
+                            connected_before = true;
+                        }
+                        Incoming::Publish(publish) => {
+                            if let (Some(subtopic), Ok(payload)) = (
+                                publish.topic.strip_prefix(&device_prefix),
+                                str::from_utf8(&publish.payload),
+                            ) {
+                                let event = update_devices(&controller.devices, subtopic, payload);
+                                if let Some(Event::DeviceDiscovered { device_id }) = &event {
+                                    // Now get everything else about the device.
+                                    controller
+                                        .subscribe(&format!("{}{}/#", device_prefix, device_id))
+                                        .await?;
+                                }
+                                if let Some(event) = event {
+                                    // It doesn't matter if nobody is listening for events.
+                                    let _ = events_tx.send(event).await;
+                                }
+                            } else {
+                                log::warn!("Unexpected publish: {:?}", publish);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(())
+            });
+        try_join_unit_handles(mqtt_task, incoming_task)
+    }
+}
+
+/// Update the model of devices based on a message received for the given subtopic (relative to the
+/// base topic), and return the event to report for it, if any.
+fn update_devices(
+    devices: &Mutex<HashMap<String, Device>>,
+    subtopic: &str,
+    payload: &str,
+) -> Option<Event> {
+    let mut devices = devices.lock().unwrap();
+    let parts = subtopic.split('/').collect::<Vec<_>>();
+    let device_id = parts[0].to_owned();
+
+    if let [_, "$homie"] = parts.as_slice() {
+        if payload.is_empty() {
+            return devices
+                .remove(&device_id)
+                .map(|_| Event::DeviceRemoved { device_id });
+        }
+        let device = devices.entry(device_id.clone()).or_insert_with(|| Device {
+            id: device_id.clone(),
+            ..Default::default()
+        });
+        let discovered = device.homie_version.is_empty();
+        device.homie_version = payload.to_owned();
+        return Some(if discovered {
+            Event::DeviceDiscovered { device_id }
+        } else {
+            Event::DeviceUpdated { device_id }
+        });
+    }
+
+    // Ignore anything for devices which we haven't seen a `$homie` for yet.
+    let device = devices.get_mut(&device_id)?;
+    let value = if payload.is_empty() {
+        None
+    } else {
+        Some(payload.to_owned())
+    };
+    if let Some((node_id, index)) = parts.get(1).and_then(|id| find_element(device, id)) {
+        let element = device
+            .nodes
+            .get_mut(&node_id)?
+            .elements
+            .entry(index)
+            .or_default();
+        match &parts[2..] {
+            ["$name"] => element.name = value,
+            [property_id] if !property_id.starts_with('$') => {
+                match value {
+                    Some(value) => element.payloads.insert((*property_id).to_owned(), value),
+                    None => element.payloads.remove(*property_id),
+                };
+                return Some(Event::PropertyValueChanged {
+                    device_id,
+                    node_id,
+                    index: Some(index),
+                    property_id: (*property_id).to_owned(),
+                    payload: payload.to_owned(),
+                });
+            }
+            _ => return None,
+        }
+        return Some(Event::DeviceUpdated { device_id });
+    }
+    match parts.as_slice() {
+        [_, "$name"] => device.name = value,
+        [_, "$state"] => device.state = State::parse(payload),
+        [_, "$implementation"] => device.implementation = value,
+        [_, "$extensions"] => device.extensions = split_list(payload),
+        [_, "$nodes"] => {
+            // Node arrays are listed with a "[]" suffix.
+            let node_ids = split_list(payload)
+                .into_iter()
+                .map(|id| id.trim_end_matches("[]").to_owned())
+                .collect::<Vec<_>>();
+            device.nodes.retain(|node_id, _| node_ids.contains(node_id));
+            for node_id in node_ids {
+                node_entry(device, &node_id);
+            }
+        }
+        // Only nodes and properties which the device lists are modelled, so anything else is ignored.
+        [_, node_id, "$name"] => device.nodes.get_mut(*node_id)?.name = value,
+        [_, node_id, "$type"] => device.nodes.get_mut(*node_id)?.node_type = value,
+        [_, node_id, "$array"] => {
+            device.nodes.get_mut(*node_id)?.array = parse_range(payload);
+            // Forget anything we saw for the elements before we knew the node was an array.
+            let element_ids = device
+                .nodes
+                .keys()
+                .filter(|id| find_element(device, id).is_some())
+                .cloned()
+                .collect::<Vec<_>>();
+            for element_id in element_ids {
+                device.nodes.remove(&element_id);
+            }
+        }
+        [_, node_id, "$properties"] => {
+            let node = device.nodes.get_mut(*node_id)?;
+            let property_ids = split_list(payload);
+            node.properties
+                .retain(|property_id, _| property_ids.contains(property_id));
+            for property_id in property_ids {
+                property_entry(node, &property_id);
+            }
+        }
+        [_, node_id, property_id] if !node_id.starts_with('$') && !property_id.starts_with('$') => {
+            find_property(device, node_id, property_id)?.payload = value;
+            return Some(Event::PropertyValueChanged {
+                device_id,
+                node_id: (*node_id).to_owned(),
+                index: None,
+                property_id: (*property_id).to_owned(),
+                payload: payload.to_owned(),
+            });
+        }
+        [_, node_id, property_id, attribute] if !node_id.starts_with('$') => {
+            let update: fn(&mut Property, Option<String>, &str) = match *attribute {
+                "$name" => |property, value, _| property.name = value,
+                "$datatype" => |property, _, payload| property.datatype = Datatype::parse(payload),
+                "$settable" => |property, _, payload| property.settable = payload == "true",
+                "$unit" => |property, value, _| property.unit = value,
+                "$format" => |property, value, _| property.format = value,
+                // Sets sent by us or other controllers, targets, and anything else we don't
+                // understand.
+                _ => return None,
+            };
+            update(find_property(device, node_id, property_id)?, value, payload);
+        }
+        // Extensions and anything else we don't understand.
+        _ => return None,
+    }
+    Some(Event::DeviceUpdated { device_id })
+}
+
+fn node_entry<'a>(device: &'a mut Device, node_id: &str) -> &'a mut Node {
+    device
+        .nodes
+        .entry(node_id.to_owned())
+        .or_insert_with(|| Node {
+            id: node_id.to_owned(),
+            ..Default::default()
+        })
+}
+
+fn property_entry<'a>(node: &'a mut Node, property_id: &str) -> &'a mut Property {
+    node.properties
+        .entry(property_id.to_owned())
+        .or_insert_with(|| Property {
+            id: property_id.to_owned(),
+            ..Default::default()
+        })
+}
+
+/// Get the given property of the device, if it has been listed by the device and its node.
+fn find_property<'a>(
+    device: &'a mut Device,
+    node_id: &str,
+    property_id: &str,
+) -> Option<&'a mut Property> {
+    device
+        .nodes
+        .get_mut(node_id)?
+        .properties
+        .get_mut(property_id)
+}
+
+/// If the given topic ID is for an element of a node array of the device, get the ID of the node
+/// and the index of the element.
+fn find_element(device: &Device, topic_id: &str) -> Option<(String, u32)> {
+    let separator = topic_id.rfind('_')?;
+    let node_id = &topic_id[..separator];
+    let index = topic_id[separator + 1..].parse().ok()?;
+    if device.nodes.get(node_id)?.array.as_ref()?.contains(&index) {
+        Some((node_id.to_owned(), index))
+    } else {
+        None
+    }
+}
+
+/// Parse the range of a node array, like "0-9".
+fn parse_range(range: &str) -> Option<RangeInclusive<u32>> {
+    let mut parts = range.splitn(2, '-');
+    let start = parts.next()?.parse().ok()?;
+    let end = parts.next()?.parse().ok()?;
+    Some(start..=end)
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update_all(devices: &Mutex<HashMap<String, Device>>, messages: &[(&str, &str)]) {
+        for (subtopic, payload) in messages {
+            update_devices(devices, subtopic, payload);
+        }
+    }
+
+    #[test]
+    fn messages_before_homie_are_ignored() {
+        let devices = Mutex::new(HashMap::new());
+
+        assert_eq!(update_devices(&devices, "device/$name", "Device"), None);
+        assert!(devices.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn device_is_discovered_and_modelled() {
+        let devices = Mutex::new(HashMap::new());
+
+        assert_eq!(
+            update_devices(&devices, "device/$homie", "4.0"),
+            Some(Event::DeviceDiscovered {
+                device_id: "device".to_string()
+            })
+        );
+        update_all(
+            &devices,
+            &[
+                ("device/$name", "Device"),
+                ("device/$state", "ready"),
+                ("device/$nodes", "light"),
+                ("device/light/$name", "Light"),
+                ("device/light/$properties", "power"),
+                ("device/light/power/$datatype", "boolean"),
+                ("device/light/power/$settable", "true"),
+                ("device/$stats/uptime", "42"),
+            ],
+        );
+        assert_eq!(
+            update_devices(&devices, "device/light/power", "true"),
+            Some(Event::PropertyValueChanged {
+                device_id: "device".to_string(),
+                node_id: "light".to_string(),
+                index: None,
+                property_id: "power".to_string(),
+                payload: "true".to_string(),
+            })
+        );
+
+        let devices = devices.lock().unwrap();
+        let device = &devices["device"];
+        assert_eq!(device.name.as_deref(), Some("Device"));
+        assert_eq!(device.state, Some(State::Ready));
+        let node = &device.nodes["light"];
+        assert_eq!(node.name.as_deref(), Some("Light"));
+        let property = &node.properties["power"];
+        assert_eq!(property.datatype, Some(Datatype::Boolean));
+        assert!(property.settable);
+        assert_eq!(property.value(), Some(Value::Boolean(true)));
+    }
+
+    #[test]
+    fn unlisted_nodes_and_properties_are_ignored() {
+        let devices = Mutex::new(HashMap::new());
+        update_all(
+            &devices,
+            &[
+                ("device/$homie", "4.0"),
+                ("device/$nodes", "light"),
+                ("device/light/$properties", "power"),
+            ],
+        );
+
+        for (subtopic, payload) in &[
+            ("device/light/power/set", "true"),
+            ("device/light/power/$target", "true"),
+            ("device/light/power/$unknown", "x"),
+            ("device/light/brightness", "50"),
+            ("device/light/brightness/$datatype", "integer"),
+            ("device/other/$name", "Other"),
+            ("device/other/power", "true"),
+            ("device/other/power/$datatype", "boolean"),
+        ] {
+            assert_eq!(
+                update_devices(&devices, subtopic, payload),
+                None,
+                "{}",
+                subtopic
+            );
+        }
+
+        let devices = devices.lock().unwrap();
+        let device = &devices["device"];
+        assert_eq!(device.nodes.keys().collect::<Vec<_>>(), vec!["light"]);
+        let node = &device.nodes["light"];
+        assert_eq!(node.properties.keys().collect::<Vec<_>>(), vec!["power"]);
+        assert_eq!(node.properties["power"].payload, None);
+    }
+
+    #[test]
+    fn node_array_elements_are_modelled_under_node() {
+        let devices = Mutex::new(HashMap::new());
+        update_all(
+            &devices,
+            &[
+                ("device/$homie", "3.0.1"),
+                ("device/$nodes", "sensors[]"),
+                ("device/sensors_1/temperature", "19.5"),
+                ("device/sensors/$name", "Sensors"),
+                ("device/sensors/$array", "0-1"),
+                ("device/sensors/$properties", "temperature"),
+                ("device/sensors/temperature/$datatype", "float"),
+                ("device/sensors_0/$name", "Kitchen"),
+            ],
+        );
+        assert_eq!(
+            update_devices(&devices, "device/sensors_1/temperature", "20.5"),
+            Some(Event::PropertyValueChanged {
+                device_id: "device".to_string(),
+                node_id: "sensors".to_string(),
+                index: Some(1),
+                property_id: "temperature".to_string(),
+                payload: "20.5".to_string(),
+            })
+        );
+
+        let devices = devices.lock().unwrap();
+        let device = &devices["device"];
+        assert_eq!(device.nodes.keys().collect::<Vec<_>>(), vec!["sensors"]);
+        let node = &device.nodes["sensors"];
+        assert_eq!(node.array, Some(0..=1));
+        assert_eq!(node.elements[&0].name.as_deref(), Some("Kitchen"));
+        assert_eq!(
+            node.element_value(1, "temperature"),
+            Some(Value::Float(20.5))
+        );
+        assert_eq!(node.element_value(0, "temperature"), None);
+    }
+
+    #[tokio::test]
+    async fn element_properties_can_be_set() -> Result<(), Error> {
+        let (requests_tx, requests_rx) = async_channel::unbounded();
+        let controller = HomieController {
+            requests_tx,
+            base_topic: "homie".to_string(),
+            devices: Default::default(),
+            subscriptions: Default::default(),
+        };
+        update_all(
+            &controller.devices,
+            &[
+                ("device/$homie", "3.0.1"),
+                ("device/$nodes", "lights[]"),
+                ("device/lights/$array", "1-2"),
+                ("device/lights/$properties", "power"),
+                ("device/lights/power/$datatype", "boolean"),
+                ("device/lights/power/$settable", "true"),
+            ],
+        );
+
+        controller
+            .set_element("device", "lights", 1, "power", true)
+            .await?;
+        controller.set("device", "lights_2", "power", false).await?;
+        let result = controller
+            .set_element("device", "lights", 3, "power", true)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::InvalidArrayIndex { index: 3, .. })
+        ));
+        let result = controller.set("device", "lights_2", "power", 42).await;
+        assert!(matches!(result, Err(Error::InvalidValue { .. })));
+
+        let mut sets = vec![];
+        while let Ok(Request::Publish(publish)) = requests_rx.try_recv() {
+            sets.push((
+                publish.topic,
+                String::from_utf8(publish.payload.to_vec()).unwrap(),
+            ));
+        }
+        assert_eq!(
+            sets,
+            vec![
+                (
+                    "homie/device/lights_1/power/set".to_string(),
+                    "true".to_string()
+                ),
+                (
+                    "homie/device/lights_2/power/set".to_string(),
+                    "false".to_string()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn removed_nodes_and_devices_are_forgotten() {
+        let devices = Mutex::new(HashMap::new());
+        update_all(
+            &devices,
+            &[
+                ("device/$homie", "4.0"),
+                ("device/$nodes", "a,b"),
+                ("device/a/$properties", "x"),
+            ],
+        );
+
+        update_devices(&devices, "device/$nodes", "b");
+        assert_eq!(
+            devices.lock().unwrap()["device"]
+                .nodes
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["b"]
+        );
+
+        assert_eq!(
+            update_devices(&devices, "device/$homie", ""),
+            Some(Event::DeviceRemoved {
+                device_id: "device".to_string()
+            })
+        );
+        assert!(devices.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! The [Homie 5](https://homieiot.github.io/specification/) `$description` document, which
+//! describes a device and all of its nodes and properties in a single retained JSON message rather
+//! than a topic per attribute.
+
+use crate::{HomieVersion, Node, Property};
+use serde_json::{json, Map, Value};
+
+/// Generate the description document for a device with the given name and nodes.
+pub(crate) fn description(device_name: &str, version: i64, nodes: &[Node]) -> Value {
+    // Homie 5 has no node arrays, so `add_node` doesn't allow them.
+    let node_descriptions: Map<String, Value> = nodes
+        .iter()
+        .map(|node| (node.id.clone(), node_description(node)))
+        .collect();
+    json!({
+        "homie": HomieVersion::V5.as_str(),
+        "version": version,
+        "name": device_name,
+        "nodes": node_descriptions,
+    })
+}
+
+fn node_description(node: &Node) -> Value {
+    let properties: Map<String, Value> = node
+        .properties
+        .iter()
+        .map(|property| (property.id.clone(), property_description(property)))
+        .collect();
+    json!({
+        "name": node.name,
+        "type": node.node_type,
+        "properties": properties,
+    })
+}
+
+fn property_description(property: &Property) -> Value {
+    let mut description = json!({
+        "name": property.name,
+        "datatype": property.datatype.as_str(),
+        "settable": property.settable,
+        "retained": property.retained,
+    });
+    if let Some(unit) = &property.unit {
+        description["unit"] = json!(unit);
+    }
+    if let Some(format) = &property.format {
+        description["format"] = json!(format);
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Datatype;
+
+    #[test]
+    fn description_for_nodes() {
+        let nodes = vec![
+            Node::new(
+                "light",
+                "Light",
+                "Dimmer",
+                vec![
+                    Property::new("power", "On", Datatype::Boolean, true, None, None).unwrap(),
+                    Property::new(
+                        "brightness",
+                        "Brightness",
+                        Datatype::Integer,
+                        false,
+                        Some("%"),
+                        Some("0:100"),
+                    )
+                    .unwrap()
+                    .with_retained(false),
+                ],
+            )
+            .unwrap(),
+            Node::new("sensor", "Sensor", "Thermometer", vec![]).unwrap(),
+        ];
+
+        let description = description("Device", 42, &nodes);
+
+        assert_eq!(
+            description,
+            json!({
+                "homie": "5.0",
+                "version": 42,
+                "name": "Device",
+                "nodes": {
+                    "light": {
+                        "name": "Light",
+                        "type": "Dimmer",
+                        "properties": {
+                            "power": {
+                                "name": "On",
+                                "datatype": "boolean",
+                                "settable": true,
+                                "retained": true,
+                            },
+                            "brightness": {
+                                "name": "Brightness",
+                                "datatype": "integer",
+                                "settable": false,
+                                "retained": false,
+                                "unit": "%",
+                                "format": "0:100",
+                            },
+                        },
+                    },
+                    "sensor": {
+                        "name": "Sensor",
+                        "type": "Thermometer",
+                        "properties": {},
+                    },
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use crate::State;
+use async_channel::SendError;
+use rumqttc::Request;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// An error from a Homie device or controller.
+#[derive(Debug)]
+pub enum Error {
+    /// The device can't move from its current state to the requested state.
+    InvalidStateTransition { from: State, to: State },
+    /// A node with the same ID has already been added to the device.
+    DuplicateNode { node_id: String },
+    /// The device has no node with the given ID.
+    UnknownNode { node_id: String },
+    /// The node is not an array, or the index is outside its range.
+    InvalidArrayIndex { node_id: String, index: u32 },
+    /// The node is an array, but the device's version of the Homie convention doesn't support
+    /// them.
+    ArrayNotSupported { node_id: String },
+    /// The given string is not a valid Homie
+    /// [topic ID](https://homieiot.github.io/specification/#topic-ids).
+    InvalidId { id: String },
+    /// There is no device with the given ID.
+    UnknownDevice { device_id: String },
+    /// A device with the same ID has already been added to the MQTT connection.
+    DuplicateDevice { device_id: String },
+    /// The device has no property with the given node and property ID.
+    UnknownProperty {
+        node_id: String,
+        property_id: String,
+    },
+    /// The property can't be set by a controller.
+    NotSettable {
+        node_id: String,
+        property_id: String,
+    },
+    /// The value is not valid for the datatype and format of the property.
+    InvalidValue {
+        node_id: String,
+        property_id: String,
+        reason: String,
+    },
+    /// The request couldn't be sent to the MQTT event loop, because it has stopped.
+    ChannelClosed(SendError<Request>),
+    /// The request couldn't be queued within the publish timeout, because the MQTT broker is
+    /// unreachable or too slow.
+    Timeout { timeout: Duration },
+    /// Looking up the local IP address or MAC address failed.
+    NetworkLookup { reason: String },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidStateTransition { from, to } => {
+                write!(f, "Can't change state from {} to {}", from, to)
+            }
+            Self::DuplicateNode { node_id } => write!(f, "Duplicate node ID {}", node_id),
+            Self::UnknownNode { node_id } => write!(f, "Unknown node {}", node_id),
+            Self::InvalidArrayIndex { node_id, index } => {
+                write!(f, "Invalid index {} for node {}", index, node_id)
+            }
+            Self::ArrayNotSupported { node_id } => write!(
+                f,
+                "Node {} is an array, which is only supported by Homie 3",
+                node_id
+            ),
+            Self::InvalidId { id } => write!(f, "Invalid topic ID {:?}", id),
+            Self::UnknownDevice { device_id } => write!(f, "Unknown device {}", device_id),
+            Self::DuplicateDevice { device_id } => write!(f, "Duplicate device ID {}", device_id),
+            Self::UnknownProperty {
+                node_id,
+                property_id,
+            } => write!(f, "Unknown property {}/{}", node_id, property_id),
+            Self::NotSettable {
+                node_id,
+                property_id,
+            } => write!(f, "Property {}/{} is not settable", node_id, property_id),
+            Self::InvalidValue {
+                node_id,
+                property_id,
+                reason,
+            } => write!(
+                f,
+                "Invalid value for property {}/{}: {}",
+                node_id, property_id, reason
+            ),
+            Self::ChannelClosed(_) => f.write_str("MQTT event loop has stopped"),
+            Self::Timeout { timeout } => {
+                write!(f, "Timed out after {:?} waiting to publish", timeout)
+            }
+            Self::NetworkLookup { reason } => write!(f, "Network lookup failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ChannelClosed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SendError<Request>> for Error {
+    fn from(e: SendError<Request>) -> Self {
+        Self::ChannelClosed(e)
+    }
+}
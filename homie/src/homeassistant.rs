@@ -0,0 +1,222 @@
+//! Publishing [Home Assistant MQTT discovery](https://www.home-assistant.io/docs/mqtt/discovery/)
+//! configuration for the properties of a Homie device, so that Home Assistant picks them up
+//! without any manual configuration.
+
+use crate::{Datatype, Node, Property};
+use serde_json::json;
+
+/// Generates Home Assistant discovery configuration for the nodes of a Homie device.
+#[derive(Clone, Debug)]
+pub(crate) struct HomeAssistantDiscovery {
+    discovery_prefix: String,
+    device_base: String,
+    device_id: String,
+    device_name: String,
+}
+
+impl HomeAssistantDiscovery {
+    pub fn new(discovery_prefix: &str, device_base: &str, device_name: &str) -> Self {
+        let device_id = device_base.rsplit('/').next().unwrap_or(device_base);
+        Self {
+            discovery_prefix: discovery_prefix.to_owned(),
+            device_base: device_base.to_owned(),
+            device_id: device_id.to_owned(),
+            device_name: device_name.to_owned(),
+        }
+    }
+
+    /// Get a discovery configuration generator with the same discovery prefix for another device.
+    pub fn for_device(&self, device_base: &str, device_name: &str) -> Self {
+        Self::new(&self.discovery_prefix, device_base, device_name)
+    }
+
+    /// Get the discovery topic and configuration payload for each property of the given node
+    /// which Home Assistant can represent. If the node is an array then there is one for each
+    /// property of each element.
+    pub fn configs(&self, node: &Node) -> Vec<(String, String)> {
+        let mut configs = vec![];
+        for (node_id, node_name) in node.instances() {
+            for property in &node.properties {
+                if let Some(component) = component(property) {
+                    let topic = self.config_topic(&node_id, property, component);
+                    let config = self.config(&node_id, &node_name, property, component);
+                    configs.push((topic, config.to_string()));
+                }
+            }
+        }
+        configs
+    }
+
+    /// Get the discovery topics for all properties of the given node.
+    pub fn config_topics(&self, node: &Node) -> Vec<String> {
+        self.configs(node)
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect()
+    }
+
+    fn config_topic(&self, node_id: &str, property: &Property, component: &str) -> String {
+        format!(
+            "{}/{}/{}/{}_{}/config",
+            self.discovery_prefix, component, self.device_id, node_id, property.id
+        )
+    }
+
+    fn config(
+        &self,
+        node_id: &str,
+        node_name: &str,
+        property: &Property,
+        component: &str,
+    ) -> serde_json::Value {
+        let state_topic = format!("{}/{}/{}", self.device_base, node_id, property.id);
+        let mut config = json!({
+            "name": format!("{} {}", node_name, property.name),
+            "unique_id": format!("{}_{}_{}", self.device_id, node_id, property.id),
+            "state_topic": state_topic,
+            "availability_topic": format!("{}/$state", self.device_base),
+            // The device is still publishing values while sleeping or in alert.
+            "availability_template":
+                "{{ 'online' if value in ['ready', 'sleeping', 'alert'] else 'offline' }}",
+            "payload_available": "online",
+            "payload_not_available": "offline",
+            "device": {
+                "identifiers": [self.device_id],
+                "name": self.device_name,
+            },
+        });
+        if property.datatype == Datatype::Boolean {
+            config["payload_on"] = json!("true");
+            config["payload_off"] = json!("false");
+        }
+        if component == "switch" {
+            config["command_topic"] = json!(format!("{}/set", state_topic));
+            config["state_on"] = json!("true");
+            config["state_off"] = json!("false");
+        }
+        if let Some(unit) = &property.unit {
+            config["unit_of_measurement"] = json!(unit);
+        }
+        if let Some(device_class) = device_class(property) {
+            config["device_class"] = json!(device_class);
+        }
+        config
+    }
+}
+
+/// The Home Assistant component type to use for the given property, or `None` if it isn't
+/// supported.
+fn component(property: &Property) -> Option<&'static str> {
+    match (property.datatype, property.settable) {
+        (Datatype::Boolean, false) => Some("binary_sensor"),
+        (Datatype::Boolean, true) => Some("switch"),
+        // Colours would need to be mapped to a light, which needs more information than we have.
+        (Datatype::Color, _) => None,
+        _ => Some("sensor"),
+    }
+}
+
+/// Guess the Home Assistant sensor device class from the unit and ID of the property.
+fn device_class(property: &Property) -> Option<&'static str> {
+    if property.datatype == Datatype::Boolean {
+        return None;
+    }
+    match property.unit.as_deref()? {
+        "°C" | "ºC" | "°F" | "ºF" => Some("temperature"),
+        // Percentages could be many things, so look at the ID to decide.
+        "%" if property.id.contains("humidity") => Some("humidity"),
+        "%" if property.id.contains("battery") => Some("battery"),
+        "V" => Some("voltage"),
+        "W" => Some("power"),
+        "kWh" => Some("energy"),
+        "Pa" | "hPa" => Some("pressure"),
+        "lx" => Some("illuminance"),
+        "dBm" => Some("signal_strength"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn sensor_node() -> Node {
+        Node::new(
+            "a4c138123456",
+            "Bedroom",
+            "Mijia sensor",
+            vec![
+                Property::new(
+                    "temperature",
+                    "Temperature",
+                    Datatype::Float,
+                    false,
+                    Some("ºC"),
+                    None,
+                )
+                .unwrap(),
+                Property::new(
+                    "battery",
+                    "Battery level",
+                    Datatype::Integer,
+                    false,
+                    Some("%"),
+                    None,
+                )
+                .unwrap(),
+                Property::new("power", "Power", Datatype::Boolean, true, None, None).unwrap(),
+                Property::new("colour", "Colour", Datatype::Color, true, None, Some("rgb"))
+                    .unwrap(),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn configs_for_sensor_node() {
+        let discovery =
+            HomeAssistantDiscovery::new("homeassistant", "homie/mijia-bridge", "Mijia bridge");
+
+        let configs = discovery.configs(&sensor_node());
+
+        let topics: Vec<&str> = configs.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/mijia-bridge/a4c138123456_temperature/config",
+                "homeassistant/sensor/mijia-bridge/a4c138123456_battery/config",
+                "homeassistant/switch/mijia-bridge/a4c138123456_power/config",
+            ]
+        );
+
+        let temperature: Value = serde_json::from_str(&configs[0].1).unwrap();
+        assert_eq!(temperature["name"], "Bedroom Temperature");
+        assert_eq!(
+            temperature["unique_id"],
+            "mijia-bridge_a4c138123456_temperature"
+        );
+        assert_eq!(
+            temperature["state_topic"],
+            "homie/mijia-bridge/a4c138123456/temperature"
+        );
+        assert_eq!(
+            temperature["availability_topic"],
+            "homie/mijia-bridge/$state"
+        );
+        assert_eq!(temperature["unit_of_measurement"], "ºC");
+        assert_eq!(temperature["device_class"], "temperature");
+        assert_eq!(temperature["device"]["identifiers"][0], "mijia-bridge");
+
+        let battery: Value = serde_json::from_str(&configs[1].1).unwrap();
+        assert_eq!(battery["device_class"], "battery");
+
+        let power: Value = serde_json::from_str(&configs[2].1).unwrap();
+        assert_eq!(
+            power["command_topic"],
+            "homie/mijia-bridge/a4c138123456/power/set"
+        );
+        assert_eq!(power["payload_on"], "true");
+        assert_eq!(power.get("device_class"), None);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::Error;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+/// A valid Homie [topic ID](https://homieiot.github.io/specification/#topic-ids), for a device,
+/// node or property.
+///
+/// Topic IDs may only contain lowercase letters from a to z, numbers from 0 to 9 and hyphens, and
+/// must not start with a hyphen.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Id(String);
+
+impl Id {
+    /// Convert an arbitrary string into a valid topic ID, by converting uppercase letters to
+    /// lowercase, replacing any other invalid characters with hyphens and removing leading
+    /// hyphens.
+    ///
+    /// Returns `None` if there is nothing left, e.g. if the string was empty.
+    pub fn sanitise(s: &str) -> Option<Id> {
+        let id: String = s
+            .chars()
+            .map(|c| {
+                let c = c.to_ascii_lowercase();
+                if is_valid_char(c) {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .skip_while(|&c| c == '-')
+            .collect();
+        if id.is_empty() {
+            None
+        } else {
+            Some(Id(id))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Check whether the given string is a valid topic ID.
+    pub(crate) fn is_valid(id: &str) -> bool {
+        !id.is_empty() && !id.starts_with('-') && id.chars().all(is_valid_char)
+    }
+}
+
+fn is_valid_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'
+}
+
+impl TryFrom<String> for Id {
+    type Error = Error;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        if Id::is_valid(&id) {
+            Ok(Id(id))
+        } else {
+            Err(Error::InvalidId { id })
+        }
+    }
+}
+
+impl TryFrom<&str> for Id {
+    type Error = Error;
+
+    fn try_from(id: &str) -> Result<Self, Self::Error> {
+        Id::try_from(id.to_owned())
+    }
+}
+
+impl AsRef<str> for Id {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Id {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Id> for String {
+    fn from(id: Id) -> Self {
+        id.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_ids() {
+        for id in &["a", "sensor-1", "0", "a4c138123456", "a-"] {
+            assert_eq!(Id::try_from(*id).unwrap().as_str(), *id);
+        }
+    }
+
+    #[test]
+    fn invalid_ids() {
+        for id in &["", "-a", "Sensor", "$state", "a_b", "a/b", "a b", "ä"] {
+            assert!(matches!(Id::try_from(*id), Err(Error::InvalidId { .. })));
+        }
+    }
+
+    #[test]
+    fn sanitise() {
+        assert_eq!(
+            Id::sanitise("A4:C1:38:12:34:56").unwrap().as_str(),
+            "a4-c1-38-12-34-56"
+        );
+        assert_eq!(
+            Id::sanitise("-$Living room").unwrap().as_str(),
+            "living-room"
+        );
+        assert_eq!(Id::sanitise("valid-id").unwrap().as_str(), "valid-id");
+        assert_eq!(Id::sanitise(""), None);
+        assert_eq!(Id::sanitise("--$"), None);
+    }
+}
@@ -1,4 +1,22 @@
-use async_channel::{SendError, Sender};
+pub mod controller;
+mod description;
+mod error;
+mod homeassistant;
+mod id;
+mod outbox;
+mod spool;
+mod stats;
+mod values;
+
+pub use crate::error::Error;
+pub use crate::id::Id;
+pub use crate::values::{Color, Value};
+
+use crate::homeassistant::HomeAssistantDiscovery;
+use crate::outbox::Outbox;
+use crate::spool::Spool;
+use crate::stats::{HomieStats, StatsProvider};
+use async_channel::Sender;
 use futures::future::try_join;
 use futures::FutureExt;
 use local_ipaddress;
@@ -6,21 +24,28 @@ use mac_address::get_mac_address;
 use rumqttc::{
     self, EventLoop, Incoming, LastWill, MqttOptions, Publish, QoS, Request, Subscribe, Unsubscribe,
 };
-use std::error::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::future::Future;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::str;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::task::{self, JoinError, JoinHandle};
-use tokio::time::delay_for;
+use tokio::time::{delay_for, timeout};
 
-const HOMIE_VERSION: &str = "4.0";
 const HOMIE_IMPLEMENTATION: &str = "homie-rs";
 const DEFAULT_FIRMWARE_NAME: &str = env!("CARGO_PKG_NAME");
 const DEFAULT_FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
-const STATS_INTERVAL: Duration = Duration::from_secs(60);
-const REQUESTS_CAP: usize = 10;
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+pub(crate) const REQUESTS_CAP: usize = 10;
+pub(crate) const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How many times to try to make the initial connection to the MQTT broker before giving up.
+const INITIAL_CONNECTION_ATTEMPTS: usize = 3;
 
 /// The data type for a Homie property.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -33,8 +58,20 @@ pub enum Datatype {
     Color,
 }
 
-impl Into<Vec<u8>> for Datatype {
-    fn into(self) -> Vec<u8> {
+impl Datatype {
+    pub(crate) fn parse(datatype: &str) -> Option<Self> {
+        match datatype {
+            "integer" => Some(Self::Integer),
+            "float" => Some(Self::Float),
+            "boolean" => Some(Self::Boolean),
+            "string" => Some(Self::String),
+            "enum" => Some(Self::Enum),
+            "color" => Some(Self::Color),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
         match self {
             Self::Integer => "integer",
             Self::Float => "float",
@@ -43,12 +80,26 @@ impl Into<Vec<u8>> for Datatype {
             Self::Enum => "enum",
             Self::Color => "color",
         }
-        .into()
     }
 }
 
+impl Into<Vec<u8>> for Datatype {
+    fn into(self) -> Vec<u8> {
+        self.as_str().into()
+    }
+}
+
+type BroadcastCallback =
+    Box<dyn Fn(String, String) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+type SetHandler = Arc<
+    dyn Fn(Value) -> Pin<Box<dyn Future<Output = Result<Option<Value>, String>> + Send>>
+        + Send
+        + Sync,
+>;
+
 /// A [property](https://homieiot.github.io/specification/#properties) of a Homie node.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Property {
     id: String,
     name: String,
@@ -56,6 +107,27 @@ pub struct Property {
     settable: bool,
     unit: Option<String>,
     format: Option<String>,
+    retained: bool,
+    qos: QoS,
+    target: bool,
+    set_handler: Option<SetHandler>,
+}
+
+impl Debug for Property {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Property")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("datatype", &self.datatype)
+            .field("settable", &self.settable)
+            .field("unit", &self.unit)
+            .field("format", &self.format)
+            .field("retained", &self.retained)
+            .field("qos", &self.qos)
+            .field("target", &self.target)
+            .field("set_handler", &self.set_handler.as_ref().map(|_| "..."))
+            .finish()
+    }
 }
 
 impl Property {
@@ -74,6 +146,8 @@ impl Property {
     ///   any other custom unit.
     /// * `format`: The format for the property, if any. This must be specified if the datatype is
     ///   `Enum` or `Color`, and may be specified if the datatype is `Integer` or `Float`.
+    ///
+    /// Returns `Error::InvalidId` if the ID is not valid.
     pub fn new(
         id: &str,
         name: &str,
@@ -81,15 +155,74 @@ impl Property {
         settable: bool,
         unit: Option<&str>,
         format: Option<&str>,
-    ) -> Property {
-        Property {
-            id: id.to_owned(),
+    ) -> Result<Property, Error> {
+        Ok(Property {
+            id: Id::try_from(id)?.into(),
             name: name.to_owned(),
             datatype,
             settable,
             unit: unit.map(|s| s.to_owned()),
             format: format.map(|s| s.to_owned()),
-        }
+            retained: true,
+            qos: QoS::AtLeastOnce,
+            target: false,
+            set_handler: None,
+        })
+    }
+
+    /// Set whether values of the property are retained by the MQTT broker. This is true by
+    /// default, but should be false for properties which represent events rather than state, like
+    /// a button being pressed.
+    pub fn with_retained(mut self, retained: bool) -> Self {
+        self.retained = retained;
+        self
+    }
+
+    /// Set the MQTT QoS level which values of the property are published with. This is
+    /// `QoS::AtLeastOnce` by default.
+    pub fn with_qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Set whether the requested value should be published to the `$target` attribute of the
+    /// property when the Homie controller tries to set it, before the set handler is called. This
+    /// is false by default.
+    ///
+    /// This is useful for properties which take a while to change, so that controllers can show
+    /// the requested value while the actual value is still catching up. The value itself is
+    /// published once the set handler returns it, or the change can be confirmed later with
+    /// `HomieDevice::publish_value`. If the set handler rejects the value then the target is
+    /// cleared.
+    pub fn with_target(mut self, target: bool) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Set a function to be called when the Homie controller tries to set the property. This also
+    /// makes the property settable.
+    ///
+    /// The handler is passed the new value, which has already been checked against the datatype
+    /// and format of the property. It should return an error if it rejects the value, or
+    /// otherwise the value to publish as the new state of the property, if any. If it returns
+    /// `Ok(None)` then the device is responsible for calling `publish_value` itself once the
+    /// value has been applied.
+    pub fn with_set_handler<F, Fut>(mut self, set_handler: F) -> Self
+    where
+        F: (Fn(Value) -> Fut) + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<Value>, String>> + Send + 'static,
+    {
+        self.settable = true;
+        self.set_handler = Some(Arc::new(move |value: Value| set_handler(value).boxed()));
+        self
+    }
+
+    fn parse_value(&self, payload: &str) -> Result<Value, String> {
+        Value::parse(payload, self.datatype, self.format.as_deref())
+    }
+
+    fn validate_value(&self, value: &Value) -> Result<(), String> {
+        value.validate(self.datatype, self.format.as_deref())
     }
 }
 
@@ -100,6 +233,14 @@ pub struct Node {
     name: String,
     node_type: String,
     properties: Vec<Property>,
+    array: Option<NodeArray>,
+}
+
+/// The range of indices of a node array, and the names of its elements.
+#[derive(Clone, Debug)]
+struct NodeArray {
+    range: RangeInclusive<u32>,
+    names: BTreeMap<u32, String>,
 }
 
 impl Node {
@@ -111,18 +252,107 @@ impl Node {
     /// * `name`: The human-readable name of the node.
     /// * `type`: The type of the node. This is an arbitrary string.
     /// * `property`: The properties of the node. There should be at least one.
-    pub fn new(id: &str, name: &str, node_type: &str, properties: Vec<Property>) -> Node {
-        Node {
-            id: id.to_owned(),
+    ///
+    /// Returns `Error::InvalidId` if the ID is not valid.
+    pub fn new(
+        id: &str,
+        name: &str,
+        node_type: &str,
+        properties: Vec<Property>,
+    ) -> Result<Node, Error> {
+        Ok(Node {
+            id: Id::try_from(id)?.into(),
             name: name.to_owned(),
             node_type: node_type.to_owned(),
             properties,
+            array: None,
+        })
+    }
+
+    /// Make the node an [array](https://homieiot.github.io/specification/spec-core-v3_0_1/#arrays)
+    /// of identical elements, with the given range of indices. Each element has the same
+    /// properties, and their values are published with `HomieDevice::publish_value_at`.
+    ///
+    /// Elements are named after the node and their index, unless a name is given with
+    /// `with_element_name`.
+    ///
+    /// Node arrays were removed in Homie 4, so they can only be added to a device using
+    /// `HomieVersion::V3`.
+    pub fn with_array(mut self, range: RangeInclusive<u32>) -> Self {
+        self.array = Some(NodeArray {
+            range,
+            names: BTreeMap::new(),
+        });
+        self
+    }
+
+    /// Set the human-readable name of the element of the node array with the given index.
+    ///
+    /// Returns `Error::InvalidArrayIndex` if the node is not an array, or the index is outside
+    /// its range.
+    pub fn with_element_name(mut self, index: u32, name: &str) -> Result<Self, Error> {
+        self.check_index(index)?;
+        if let Some(array) = &mut self.array {
+            array.names.insert(index, name.to_owned());
+        }
+        Ok(self)
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), Error> {
+        match &self.array {
+            Some(array) if array.range.contains(&index) => Ok(()),
+            _ => Err(Error::InvalidArrayIndex {
+                node_id: self.id.clone(),
+                index,
+            }),
+        }
+    }
+
+    /// The topic ID under which the values of the element with the given index are published.
+    fn element_id(&self, index: u32) -> String {
+        format!("{}_{}", self.id, index)
+    }
+
+    /// Whether the given topic ID is for this node, or one of its elements if it is an array.
+    fn has_topic_id(&self, topic_id: &str) -> bool {
+        if topic_id == self.id {
+            return true;
+        }
+        match (&self.array, topic_id.rfind('_')) {
+            (Some(array), Some(separator)) => {
+                let index = topic_id[separator + 1..].parse::<u32>();
+                topic_id[..separator] == self.id
+                    && matches!(index, Ok(index) if array.range.contains(&index))
+            }
+            _ => false,
+        }
+    }
+
+    /// The topic ID and name of each instance of the node under which property values are
+    /// published. This is just the node itself, unless it is an array in which case it is each of
+    /// the elements.
+    pub(crate) fn instances(&self) -> Vec<(String, String)> {
+        match &self.array {
+            None => vec![(self.id.clone(), self.name.clone())],
+            Some(array) => array
+                .range
+                .clone()
+                .map(|index| {
+                    let name = array
+                        .names
+                        .get(&index)
+                        .cloned()
+                        .unwrap_or_else(|| format!("{} {}", self.name, index));
+                    (self.element_id(index), name)
+                })
+                .collect(),
         }
     }
 }
 
+/// The [state](https://homieiot.github.io/specification/#device-lifecycle) of a Homie device.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum State {
+pub enum State {
     Init,
     Ready,
     Disconnected,
@@ -142,6 +372,18 @@ impl State {
             Self::Alert => "alert",
         }
     }
+
+    pub(crate) fn parse(state: &str) -> Option<Self> {
+        match state {
+            "init" => Some(Self::Init),
+            "ready" => Some(Self::Ready),
+            "disconnected" => Some(Self::Disconnected),
+            "sleeping" => Some(Self::Sleeping),
+            "lost" => Some(Self::Lost),
+            "alert" => Some(Self::Alert),
+            _ => None,
+        }
+    }
 }
 
 impl Display for State {
@@ -156,11 +398,55 @@ impl Into<Vec<u8>> for State {
     }
 }
 
-type UpdateCallback = Box<
-    dyn FnMut(String, String, String) -> Pin<Box<dyn Future<Output = Option<String>> + Send>>
-        + Send
-        + Sync,
->;
+/// The version of the [Homie convention](https://homieiot.github.io/) which a device implements.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HomieVersion {
+    /// [Homie 3.0.1](https://homieiot.github.io/specification/spec-core-v3_0_1/), for older
+    /// controllers which don't support Homie 4. The stats and firmware attributes are part of the
+    /// convention rather than extensions.
+    V3,
+    /// [Homie 4.0](https://homieiot.github.io/specification/spec-core-v4_0_0/). This is the
+    /// default.
+    V4,
+    /// [Homie 5.0](https://homieiot.github.io/specification/), where the device is described by a
+    /// single `$description` JSON document. Devices are under a `5` level below the Homie base
+    /// topic, there is no alert state, and the stats and firmware extensions are not published.
+    V5,
+}
+
+impl HomieVersion {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::V3 => "3.0.1",
+            Self::V4 => "4.0",
+            Self::V5 => "5.0",
+        }
+    }
+
+    /// Get the topic for a device with the given device base (e.g. "homie/my-device") under this
+    /// version of the convention.
+    fn device_base(&self, device_base: &str) -> String {
+        match (self, device_base.rfind('/')) {
+            (Self::V5, Some(index)) => {
+                format!("{}/5/{}", &device_base[..index], &device_base[index + 1..])
+            }
+            (Self::V5, None) => format!("5/{}", device_base),
+            _ => device_base.to_owned(),
+        }
+    }
+}
+
+impl Default for HomieVersion {
+    fn default() -> Self {
+        Self::V4
+    }
+}
+
+impl Display for HomieVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
 /// Builder for `HomieDevice` and associated objects.
 pub struct HomieDeviceBuilder {
@@ -169,7 +455,15 @@ pub struct HomieDeviceBuilder {
     firmware_name: String,
     firmware_version: String,
     mqtt_options: MqttOptions,
-    update_callback: Option<UpdateCallback>,
+    homie_version: HomieVersion,
+    republish_values: bool,
+    broadcast_callback: Option<BroadcastCallback>,
+    home_assistant_discovery_prefix: Option<String>,
+    stats_interval: Duration,
+    stats_providers: Vec<(String, StatsProvider)>,
+    queue_capacity: usize,
+    publish_timeout: Option<Duration>,
+    spool: Option<(PathBuf, u64)>,
 }
 
 impl Debug for HomieDeviceBuilder {
@@ -180,10 +474,28 @@ impl Debug for HomieDeviceBuilder {
             .field("firmware_name", &self.firmware_name)
             .field("firmware_version", &self.firmware_version)
             .field("mqtt_options", &self.mqtt_options)
+            .field("homie_version", &self.homie_version)
+            .field("republish_values", &self.republish_values)
+            .field(
+                "broadcast_callback",
+                &self.broadcast_callback.as_ref().map(|_| "..."),
+            )
             .field(
-                "update_callback",
-                &self.update_callback.as_ref().map(|_| "..."),
+                "home_assistant_discovery_prefix",
+                &self.home_assistant_discovery_prefix,
             )
+            .field("stats_interval", &self.stats_interval)
+            .field(
+                "stats_providers",
+                &self
+                    .stats_providers
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("queue_capacity", &self.queue_capacity)
+            .field("publish_timeout", &self.publish_timeout)
+            .field("spool", &self.spool)
             .finish()
     }
 }
@@ -197,16 +509,98 @@ impl HomieDeviceBuilder {
         self.firmware_version = firmware_version.to_string();
     }
 
-    pub fn set_update_callback<F, Fut>(&mut self, mut update_callback: F)
+    /// Set which version of the Homie convention the device should implement. If this is not set,
+    /// it will default to Homie 4.0.
+    ///
+    /// For Homie 5 the device is published under a `5` level, so a device base of
+    /// "homie/my-device" will be published under "homie/5/my-device".
+    pub fn set_homie_version(&mut self, homie_version: HomieVersion) {
+        self.homie_version = homie_version;
+    }
+
+    /// Set whether the last known value of each property should be re-published along with the
+    /// rest of the device description after reconnecting to the MQTT broker.
+    ///
+    /// This is useful if the broker doesn't persist retained messages across restarts, but means
+    /// that stale values may be re-published. It defaults to false.
+    pub fn set_republish_values(&mut self, republish_values: bool) {
+        self.republish_values = republish_values;
+    }
+
+    /// Set a function to be called when a
+    /// [broadcast message](https://homieiot.github.io/specification/#broadcast-channel) is
+    /// received from the Homie controller. If this is set then the device will subscribe to
+    /// broadcasts under its base topic.
+    ///
+    /// The callback is passed the broadcast level (i.e. the subtopic after `$broadcast/`, such as
+    /// "alert") and the payload.
+    pub fn set_broadcast_callback<F, Fut>(&mut self, broadcast_callback: F)
     where
-        F: (FnMut(String, String, String) -> Fut) + Send + Sync + 'static,
-        Fut: Future<Output = Option<String>> + Send + 'static,
+        F: (Fn(String, String) -> Fut) + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
     {
-        self.update_callback = Some(Box::new(
-            move |node_id: String, property_id: String, value: String| {
-                update_callback(node_id, property_id, value).boxed()
-            },
-        ));
+        self.broadcast_callback = Some(Box::new(move |level: String, payload: String| {
+            broadcast_callback(level, payload).boxed()
+        }));
+    }
+
+    /// Also publish [Home Assistant MQTT discovery](https://www.home-assistant.io/docs/mqtt/discovery/)
+    /// configuration for each property, under the given discovery prefix. This is usually
+    /// "homeassistant".
+    ///
+    /// Boolean properties will be configured as binary sensors, or switches if they are settable.
+    /// Colour properties are not supported, and all other properties will be configured as
+    /// sensors.
+    pub fn set_home_assistant_discovery(&mut self, discovery_prefix: &str) {
+        self.home_assistant_discovery_prefix = Some(discovery_prefix.to_string());
+    }
+
+    /// Set how often stats should be published. If this is not set, it will default to once a
+    /// minute.
+    pub fn set_stats_interval(&mut self, stats_interval: Duration) {
+        self.stats_interval = stats_interval;
+    }
+
+    /// Add a function to provide a value for the stat with the given name, which will be published
+    /// under `$stats/<name>` along with the other stats. If it returns `None` then nothing will be
+    /// published for the stat that time.
+    ///
+    /// The signal strength, CPU temperature, CPU load, battery level, free memory and supply
+    /// voltage are collected automatically on Linux if they are available, but a provider with the
+    /// same name will take precedence.
+    pub fn add_stats_provider<F>(&mut self, name: &str, provider: F)
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.stats_providers
+            .push((name.to_string(), Box::new(provider)));
+    }
+
+    /// Set how many messages may be queued while the connection to the MQTT broker is down. Once
+    /// the queue is full, publishing blocks until the connection is re-established. If this is not
+    /// set, it will default to 1000.
+    ///
+    /// While the connection is down, a retained message replaces any older retained message for
+    /// the same topic which is still queued, as only the latest one matters.
+    pub fn set_queue_capacity(&mut self, queue_capacity: usize) {
+        self.queue_capacity = queue_capacity;
+    }
+
+    /// Set how long to wait for space in the queue when publishing, before giving up with
+    /// `Error::Timeout`. If this is not set then publishing will wait indefinitely.
+    pub fn set_publish_timeout(&mut self, publish_timeout: Duration) {
+        self.publish_timeout = Some(publish_timeout);
+    }
+
+    /// Spool messages to the given file while the connection to the MQTT broker is down, rather
+    /// than queueing them in memory, so that they aren't lost if the process is restarted.
+    ///
+    /// Once the connection is established the spooled messages are sent in order, but with only
+    /// the latest retained message for each topic. If the file grows beyond `max_size` bytes then
+    /// superseded retained messages and then the oldest messages are dropped, until it is down to
+    /// half of `max_size`.
+    pub fn set_spool(&mut self, path: impl Into<PathBuf>, max_size: u64) {
+        self.spool = Some((path.into(), max_size));
     }
 
     /// Create a new Homie device, connect to the MQTT server, and start a task to handle the MQTT
@@ -215,22 +609,39 @@ impl HomieDeviceBuilder {
     /// # Return value
     /// A pair of the `HomieDevice` itself, and a `Future` for the tasks which handle the MQTT
     /// connection. You should join on this future to handle any errors it returns.
+    ///
+    /// Once connected, the tasks will keep trying to reconnect if the connection to the MQTT broker
+    /// is lost. However, if the initial connection fails several times in a row then they will give
+    /// up and the future will resolve with the error, as the broker address or credentials are
+    /// probably wrong.
     pub async fn spawn(
         self,
     ) -> Result<
         (
             HomieDevice,
-            impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>>,
+            impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>>,
         ),
-        SendError<Request>,
+        Error,
     > {
-        let (event_loop, mut homie, stats, firmware, update_callback) = self.build();
+        // The device ID must be valid, so that controllers can find the device.
+        Id::try_from(self.device_base.rsplit('/').next().unwrap_or_default())?;
+        let republish_values = self.republish_values;
+        let (event_loop, mut homie, stats, firmware, broadcast_callback) = self.build();
+        let broadcast_topic = broadcast_callback
+            .as_ref()
+            .map(|_| format!("{}/$broadcast/#", homie.publisher.homie_base()));
 
         // This needs to be spawned before we wait for anything to be sent, as the start() calls below do.
-        let event_task = homie.spawn(event_loop, update_callback);
+        let event_task = homie.spawn(event_loop, republish_values, broadcast_callback);
 
+        if let Some(broadcast_topic) = broadcast_topic {
+            homie.publisher.subscribe_topic(&broadcast_topic).await?;
+        }
         stats.start().await?;
-        firmware.start().await?;
+        // Homie 5 devices don't implement the legacy firmware extension.
+        if homie.homie_version != HomieVersion::V5 {
+            firmware.start().await?;
+        }
         homie.start().await?;
 
         let stats_task = stats.spawn();
@@ -246,38 +657,74 @@ impl HomieDeviceBuilder {
         HomieDevice,
         HomieStats,
         HomieFirmware,
-        Option<UpdateCallback>,
+        Option<BroadcastCallback>,
     ) {
+        let device_base = self.homie_version.device_base(&self.device_base);
+        let home_assistant = self
+            .home_assistant_discovery_prefix
+            .as_ref()
+            .map(|prefix| HomeAssistantDiscovery::new(prefix, &device_base, &self.device_name));
         let mut mqtt_options = self.mqtt_options;
         mqtt_options.set_last_will(LastWill::new(
-            format!("{}/$state", self.device_base),
+            format!("{}/$state", device_base),
             State::Lost,
             QoS::AtLeastOnce,
             true,
         ));
         let event_loop = EventLoop::new(mqtt_options, REQUESTS_CAP);
 
-        let publisher = DevicePublisher::new(event_loop.handle(), self.device_base);
-        let homie = HomieDevice::new(publisher.clone(), self.device_name, &EXTENSION_IDS);
-
-        let stats = HomieStats::new(publisher.clone());
+        let (requests_tx, outbox) = Outbox::new(self.queue_capacity);
+        if let Some((path, max_size)) = self.spool {
+            outbox.set_spool(Spool::new(path, max_size));
+        }
+        let mut publisher = DevicePublisher::new(requests_tx, device_base);
+        publisher.outbox = Some(outbox);
+        publisher.publish_timeout = self.publish_timeout;
+        let mut homie = HomieDevice::new(publisher.clone(), self.device_name, &EXTENSION_IDS);
+        homie.homie_version = self.homie_version;
+        homie.home_assistant = home_assistant;
+
+        let stats = HomieStats::new(
+            publisher.clone(),
+            self.homie_version,
+            self.stats_interval,
+            self.stats_providers,
+        );
         let firmware = HomieFirmware::new(publisher, self.firmware_name, self.firmware_version);
 
-        (event_loop, homie, stats, firmware, self.update_callback)
+        (event_loop, homie, stats, firmware, self.broadcast_callback)
     }
 }
 
 const EXTENSION_IDS: [&str; 2] = [HomieStats::EXTENSION_ID, HomieFirmware::EXTENSION_ID];
 
-/// A Homie [device](https://homieiot.github.io/specification/#devices). This corresponds to a
-/// single MQTT connection.
+/// The nodes of a device, shared with the task which handles incoming messages.
+type SharedNodes = Arc<Mutex<Vec<Node>>>;
+
+/// The devices which share an MQTT connection, along with their nodes, so that incoming messages
+/// can be routed to the right device.
+type SharedDevices = Arc<Mutex<Vec<(DevicePublisher, SharedNodes)>>>;
+
+/// A Homie [device](https://homieiot.github.io/specification/#devices). This usually corresponds
+/// to a single MQTT connection, but other devices can be added to share the connection with
+/// `add_device`.
 #[derive(Debug)]
 pub struct HomieDevice {
     publisher: DevicePublisher,
     device_name: String,
-    nodes: Vec<Node>,
+    /// This is shared with the task which handles incoming messages, so that it can check the
+    /// values it receives against the property definitions.
+    nodes: Arc<Mutex<Vec<Node>>>,
     state: State,
+    homie_version: HomieVersion,
     extension_ids: String,
+    home_assistant: Option<HomeAssistantDiscovery>,
+    /// The version of the Homie 5 `$description`, which must change whenever it does.
+    description_version: i64,
+    /// All the devices on the same MQTT connection, including this one while it is connected.
+    devices: SharedDevices,
+    /// Whether this device made the MQTT connection, rather than being added to it later.
+    owns_connection: bool,
 }
 
 impl HomieDevice {
@@ -301,28 +748,102 @@ impl HomieDevice {
             firmware_name: DEFAULT_FIRMWARE_NAME.to_string(),
             firmware_version: DEFAULT_FIRMWARE_VERSION.to_string(),
             mqtt_options,
-            update_callback: None,
+            homie_version: HomieVersion::default(),
+            republish_values: false,
+            broadcast_callback: None,
+            home_assistant_discovery_prefix: None,
+            stats_interval: DEFAULT_STATS_INTERVAL,
+            stats_providers: vec![],
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            publish_timeout: None,
+            spool: None,
         }
     }
 
     fn new(publisher: DevicePublisher, device_name: String, extension_ids: &[&str]) -> HomieDevice {
+        let nodes: SharedNodes = Default::default();
+        let devices = Arc::new(Mutex::new(vec![(publisher.clone(), nodes.clone())]));
         HomieDevice {
             publisher,
             device_name,
-            nodes: vec![],
+            nodes,
             state: State::Disconnected,
+            homie_version: HomieVersion::default(),
             extension_ids: extension_ids.join(","),
+            home_assistant: None,
+            // The version doesn't need to be sequential, but it shouldn't repeat across restarts.
+            description_version: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs() as i64),
+            devices,
+            owns_connection: true,
         }
     }
 
-    async fn start(&mut self) -> Result<(), SendError<Request>> {
-        assert_eq!(self.state, State::Disconnected);
-        self.publisher
-            .publish_retained("$homie", HOMIE_VERSION)
-            .await?;
+    /// Add another Homie device which shares the MQTT connection of this one, and start it. It
+    /// has its own nodes and state, but no stats or firmware attributes. Otherwise it is
+    /// configured the same way as this device.
+    ///
+    /// An MQTT connection can only have one last will, which is used for the device which made the
+    /// connection. This means that if the connection is lost unexpectedly, e.g. because the process
+    /// crashes or the network goes down, only that device's state will be set to 'lost' by the
+    /// broker. Added devices will still appear to be 'ready' to controllers until the connection is
+    /// re-established, so controllers which need to notice the loss of an added device should also
+    /// watch the state of the device which made the connection. As a fallback, if an added device
+    /// is dropped without being disconnected then its state is set to 'lost'.
+    ///
+    /// # Arguments
+    /// * `device_base`: The base topic ID for the device, including the Homie base topic, like for
+    ///   `HomieDevice::builder`. This must be different to all other devices on the connection.
+    /// * `device_name`: The human-readable name of the device.
+    pub async fn add_device(
+        &self,
+        device_base: &str,
+        device_name: &str,
+    ) -> Result<HomieDevice, Error> {
+        let device_id = Id::try_from(device_base.rsplit('/').next().unwrap_or_default())?;
+        let device_base = self.homie_version.device_base(device_base);
+        let publisher = self.publisher.for_device(device_base);
+        let mut device = HomieDevice::new(publisher, device_name.to_owned(), &[]);
+        device.homie_version = self.homie_version;
+        device.home_assistant = self.home_assistant.as_ref().map(|home_assistant| {
+            home_assistant.for_device(&device.publisher.device_base, device_name)
+        });
+        device.owns_connection = false;
+        {
+            let mut devices = self.devices.lock().unwrap();
+            if devices
+                .iter()
+                .any(|(publisher, _)| publisher.device_base == device.publisher.device_base)
+            {
+                return Err(Error::DuplicateDevice {
+                    device_id: device_id.into(),
+                });
+            }
+            devices.push((device.publisher.clone(), device.nodes.clone()));
+        }
+        device.devices = self.devices.clone();
+        device.start().await?;
+        Ok(device)
+    }
+
+    async fn start(&mut self) -> Result<(), Error> {
+        self.check_state(&[State::Disconnected], State::Init)?;
+        if self.homie_version == HomieVersion::V5 {
+            // Everything else is part of the description.
+            self.publish_description().await?;
+            self.set_state(State::Init).await?;
+            return Ok(());
+        }
         self.publisher
-            .publish_retained("$extensions", self.extension_ids.as_str())
+            .publish_retained("$homie", self.homie_version.as_str())
             .await?;
+        // Homie 3 has no extensions, the stats and firmware attributes are part of the convention.
+        if self.homie_version == HomieVersion::V4 && !self.extension_ids.is_empty() {
+            self.publisher
+                .publish_retained("$extensions", self.extension_ids.as_str())
+                .await?;
+        }
         self.publisher
             .publish_retained("$implementation", HOMIE_IMPLEMENTATION)
             .await?;
@@ -334,59 +855,129 @@ impl HomieDevice {
     }
 
     /// Spawn a task to handle the EventLoop.
+    ///
+    /// If the connection to the MQTT broker is lost then the task will keep trying to reconnect,
+    /// and re-publish everything once it succeeds.
     fn spawn(
         &self,
         mut event_loop: EventLoop,
-        mut update_callback: Option<UpdateCallback>,
-    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> {
-        let device_base = format!("{}/", self.publisher.device_base);
+        republish_values: bool,
+        broadcast_callback: Option<BroadcastCallback>,
+    ) -> impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>> {
+        let broadcast_base = format!("{}/$broadcast/", self.publisher.homie_base());
         let (incoming_tx, incoming_rx) = async_channel::unbounded();
 
-        let mqtt_task = task::spawn(async move {
-            loop {
-                let (incoming, outgoing) = event_loop.poll().await?;
-                log::trace!("Incoming = {:?}, Outgoing = {:?}", incoming, outgoing);
-
-                if let Some(incoming) = incoming {
-                    incoming_tx.send(incoming).await?;
+        let outbox = self.publisher.outbox.clone();
+        let outbox_task: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> = {
+            let outbox = outbox.clone();
+            let event_loop_tx = event_loop.handle();
+            task::spawn(async move {
+                if let Some(outbox) = outbox {
+                    outbox.run(event_loop_tx).await;
                 }
-            }
-        });
-
+                Ok(())
+            })
+        };
         let publisher = self.publisher.clone();
-        let incoming_task: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> =
+        let mqtt_task: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
             task::spawn(async move {
+                let mut connected_before = false;
+                let mut failed_attempts = 0;
                 loop {
-                    match incoming_rx.recv().await? {
+                    match event_loop.poll().await {
+                        Ok((incoming, outgoing)) => {
+                            log::trace!("Incoming = {:?}, Outgoing = {:?}", incoming, outgoing);
+
+                            if let Some(Incoming::ConnAck(_)) = &incoming {
+                                connected_before = true;
+                                if let Some(outbox) = &outbox {
+                                    outbox.set_connected(true);
+                                }
+                            }
+                            if let Some(incoming) = incoming {
+                                incoming_tx.send(incoming).await?;
+                            }
+                        }
+                        Err(e) if publisher.is_disconnecting() => {
+                            log::trace!("Connection closed after disconnect: {}", e);
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            if let Some(outbox) = &outbox {
+                                outbox.set_connected(false);
+                            }
+                            if !connected_before {
+                                failed_attempts += 1;
+                                if failed_attempts >= INITIAL_CONNECTION_ATTEMPTS {
+                                    log::error!(
+                                        "Failed to connect to MQTT broker {} times, giving up: {}",
+                                        failed_attempts,
+                                        e
+                                    );
+                                    return Err(e.into());
+                                }
+                            }
+                            log::error!(
+                                "MQTT connection failed, reconnecting in {:?}: {}",
+                                RECONNECT_INTERVAL,
+                                e
+                            );
+                            delay_for(RECONNECT_INTERVAL).await;
+                        }
+                    }
+                }
+            });
+
+        let devices = self.devices.clone();
+        let incoming_task: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
+            task::spawn(async move {
+                let mut connected_before = false;
+                while let Ok(incoming) = incoming_rx.recv().await {
+                    match incoming {
+                        Incoming::ConnAck(_) => {
+                            // Everything is published as part of the initial startup, but after a
+                            // reconnect our last will has probably set the state to lost, and the
+                            // broker may have forgotten our subscriptions and retained messages.
+                            if connected_before {
+                                log::info!("Reconnected to MQTT broker, re-publishing devices");
+                                let publishers = devices
+                                    .lock()
+                                    .unwrap()
+                                    .iter()
+                                    .map(|(publisher, _)| publisher.clone())
+                                    .collect::<Vec<_>>();
+                                for publisher in publishers {
+                                    ignore_timeout(publisher.republish(republish_values).await)?;
+                                }
+                            }
+                            connected_before = true;
+                        }
                         Incoming::Publish(publish) => {
-                            if let Some(rest) = publish.topic.strip_prefix(&device_base) {
+                            if let Some((publisher, nodes, rest)) =
+                                find_device(&devices, &publish.topic)
+                            {
                                 if let ([node_id, property_id, "set"], Ok(payload)) = (
                                     rest.split("/").collect::<Vec<&str>>().as_slice(),
                                     str::from_utf8(&publish.payload),
                                 ) {
-                                    log::trace!(
-                                        "set node {:?} property {:?} to {:?}",
-                                        node_id,
-                                        property_id,
-                                        payload
-                                    );
-                                    if let Some(callback) = update_callback.as_mut() {
-                                        if let Some(value) = callback(
-                                            node_id.to_string(),
-                                            property_id.to_string(),
-                                            payload.to_string(),
+                                    ignore_timeout(
+                                        handle_set(
+                                            &publisher,
+                                            &nodes,
+                                            node_id,
+                                            property_id,
+                                            payload,
                                         )
-                                        .await
-                                        {
-                                            publisher
-                                                .publish_retained(
-                                                    &format!("{}/{}", node_id, property_id),
-                                                    value,
-                                                )
-                                                .await?;
-                                        }
-                                    }
+                                        .await,
+                                    )?;
                                 }
+                            } else if let (Some(level), Some(callback), Ok(payload)) = (
+                                publish.topic.strip_prefix(&broadcast_base),
+                                broadcast_callback.as_ref(),
+                                str::from_utf8(&publish.payload),
+                            ) {
+                                log::trace!("broadcast {:?}: {:?}", level, payload);
+                                callback(level.to_owned(), payload.to_owned()).await;
                             } else {
                                 log::warn!("Unexpected publish: {:?}", publish);
                             }
@@ -394,44 +985,101 @@ impl HomieDevice {
                         _ => {}
                     }
                 }
+                Ok(())
             });
-        try_join_unit_handles(mqtt_task, incoming_task)
+        // If the outbox task stops unexpectedly then publishing would block forever, so make sure
+        // the caller finds out.
+        try_join(
+            try_join_unit_handles(mqtt_task, incoming_task),
+            outbox_task.map(|res| res?),
+        )
+        .map(simplify_unit_pair)
     }
 
     /// Add a node to the Homie device. It will immediately be published.
     ///
-    /// This will panic if you attempt to add a node with the same ID as a node which was previously
-    /// added.
-    pub async fn add_node(&mut self, node: Node) -> Result<(), SendError<Request>> {
-        // First check that there isn't already a node with the same ID.
-        if self.nodes.iter().any(|n| n.id == node.id) {
-            panic!("Tried to add node with duplicate ID: {:?}", node);
-        }
-        self.nodes.push(node);
-        // `node` was moved into the `nodes` vector, but we can safely get a reference to it because
-        // nothing else can modify `nodes` in the meantime.
-        let node = &self.nodes[self.nodes.len() - 1];
+    /// This will return an error if you attempt to add a node with the same ID as a node which was
+    /// previously added, or a node array to a device which isn't using Homie 3.
+    pub async fn add_node(&mut self, node: Node) -> Result<(), Error> {
+        if node.array.is_some() && self.homie_version != HomieVersion::V3 {
+            return Err(Error::ArrayNotSupported { node_id: node.id });
+        }
+        {
+            let mut nodes = self.nodes.lock().unwrap();
+            // First check that there isn't already a node with the same ID.
+            if nodes.iter().any(|n| n.id == node.id) {
+                return Err(Error::DuplicateNode { node_id: node.id });
+            }
+            nodes.push(node.clone());
+        }
 
         self.publish_node(&node).await?;
-        self.publish_nodes().await
+        self.publish_nodes().await?;
+        Ok(())
+    }
+
+    /// Remove the node with the given ID. All retained messages which were published for it will
+    /// be cleared from the MQTT broker.
+    pub async fn remove_node(&mut self, node_id: &str) -> Result<(), Error> {
+        let node =
+            {
+                let mut nodes = self.nodes.lock().unwrap();
+                let index = nodes.iter().position(|n| n.id == node_id).ok_or_else(|| {
+                    Error::UnknownNode {
+                        node_id: node_id.to_owned(),
+                    }
+                })?;
+                nodes.remove(index)
+            };
+        self.unpublish_node(&node).await?;
+        self.publish_nodes().await?;
+        Ok(())
     }
 
-    /// Remove the node with the given ID.
-    pub async fn remove_node(&mut self, node_id: &str) -> Result<(), SendError<Request>> {
-        // Panic on attempt to remove a node which was never added.
-        let index = self.nodes.iter().position(|n| n.id == node_id).unwrap();
-        self.unpublish_node(&self.nodes[index]).await?;
-        self.nodes.remove(index);
-        self.publish_nodes().await
+    async fn publish_node(&self, node: &Node) -> Result<(), Error> {
+        // Homie 5 describes nodes in the device description instead.
+        if self.homie_version != HomieVersion::V5 {
+            self.publish_node_attributes(node).await?;
+        }
+        for property in &node.properties {
+            if property.settable {
+                for (instance_id, _) in node.instances() {
+                    self.publisher
+                        .subscribe(&format!("{}/{}/set", instance_id, property.id))
+                        .await?;
+                }
+            }
+        }
+        if let Some(home_assistant) = &self.home_assistant {
+            for (topic, config) in home_assistant.configs(node) {
+                self.publisher
+                    .publish_retained_topic(&topic, config)
+                    .await?;
+            }
+        }
+        Ok(())
     }
 
-    async fn publish_node(&self, node: &Node) -> Result<(), SendError<Request>> {
+    async fn publish_node_attributes(&self, node: &Node) -> Result<(), Error> {
         self.publisher
             .publish_retained(&format!("{}/$name", node.id), node.name.as_str())
             .await?;
         self.publisher
             .publish_retained(&format!("{}/$type", node.id), node.node_type.as_str())
             .await?;
+        if let Some(array) = &node.array {
+            self.publisher
+                .publish_retained(
+                    &format!("{}/$array", node.id),
+                    format!("{}-{}", array.range.start(), array.range.end()),
+                )
+                .await?;
+            for (element_id, name) in node.instances() {
+                self.publisher
+                    .publish_retained(&format!("{}/$name", element_id), name)
+                    .await?;
+            }
+        }
         let mut property_ids: Vec<&str> = vec![];
         for property in &node.properties {
             property_ids.push(&property.id);
@@ -453,6 +1101,12 @@ impl HomieDevice {
                     if property.settable { "true" } else { "false" },
                 )
                 .await?;
+            self.publisher
+                .publish_retained(
+                    &format!("{}/{}/$retained", node.id, property.id),
+                    if property.retained { "true" } else { "false" },
+                )
+                .await?;
             if let Some(unit) = &property.unit {
                 self.publisher
                     .publish_retained(&format!("{}/{}/$unit", node.id, property.id), unit.as_str())
@@ -466,11 +1120,6 @@ impl HomieDevice {
                     )
                     .await?;
             }
-            if property.settable {
-                self.publisher
-                    .subscribe(&format!("{}/{}/set", node.id, property.id))
-                    .await?;
-            }
         }
         self.publisher
             .publish_retained(&format!("{}/$properties", node.id), property_ids.join(","))
@@ -478,80 +1127,450 @@ impl HomieDevice {
         Ok(())
     }
 
-    async fn unpublish_node(&self, node: &Node) -> Result<(), SendError<Request>> {
+    /// Clear all retained messages which may have been published for the given node, which must
+    /// not currently be added to the device. This is useful to clean up after a node which was
+    /// added by a previous run of the program but won't be added again.
+    ///
+    /// This will return an error if there is currently a node with the same ID.
+    pub async fn purge_node(&self, node: &Node) -> Result<(), Error> {
+        if self.nodes.lock().unwrap().iter().any(|n| n.id == node.id) {
+            return Err(Error::DuplicateNode {
+                node_id: node.id.clone(),
+            });
+        }
+        self.unpublish_node(node).await?;
+        Ok(())
+    }
+
+    async fn unpublish_node(&self, node: &Node) -> Result<(), Error> {
         for property in &node.properties {
             if property.settable {
+                for (instance_id, _) in node.instances() {
+                    self.publisher
+                        .unsubscribe(&format!("{}/{}/set", instance_id, property.id))
+                        .await?;
+                }
+            }
+        }
+        // Clear everything we know we published under the node, as well as everything which the
+        // node could have published, in case it was from before we started.
+        let mut subtopics = self.publisher.retained_subtopics(&format!("{}/", node.id));
+        for (instance_id, _) in node.instances() {
+            subtopics.extend(
                 self.publisher
-                    .unsubscribe(&format!("{}/{}/set", node.id, property.id))
-                    .await?;
+                    .retained_subtopics(&format!("{}/", instance_id)),
+            );
+        }
+        // Homie 5 devices never publish attributes for nodes, only values and targets.
+        let is_v5 = self.homie_version == HomieVersion::V5;
+        subtopics.extend(node_subtopics(node).into_iter().filter(|subtopic| {
+            !is_v5 || !subtopic.contains('$') || subtopic.ends_with("/$target")
+        }));
+        subtopics.sort();
+        subtopics.dedup();
+        for subtopic in subtopics {
+            self.publisher.clear_retained(&subtopic).await?;
+        }
+        if let Some(home_assistant) = &self.home_assistant {
+            for topic in home_assistant.config_topics(node) {
+                self.publisher.clear_retained_topic(&topic).await?;
             }
         }
         Ok(())
     }
 
-    async fn publish_nodes(&mut self) -> Result<(), SendError<Request>> {
+    async fn publish_nodes(&mut self) -> Result<(), Error> {
+        if self.homie_version == HomieVersion::V5 {
+            self.description_version += 1;
+            return self.publish_description().await;
+        }
         let node_ids = self
             .nodes
+            .lock()
+            .unwrap()
             .iter()
-            .map(|node| node.id.as_str())
-            .collect::<Vec<&str>>()
+            .map(|node| {
+                // Node arrays are listed with a "[]" suffix.
+                if node.array.is_some() {
+                    format!("{}[]", node.id)
+                } else {
+                    node.id.clone()
+                }
+            })
+            .collect::<Vec<_>>()
             .join(",");
         self.publisher.publish_retained("$nodes", node_ids).await
     }
 
-    async fn set_state(&mut self, state: State) -> Result<(), SendError<Request>> {
+    async fn publish_description(&self) -> Result<(), Error> {
+        let description = description::description(
+            &self.device_name,
+            self.description_version,
+            &self.nodes.lock().unwrap(),
+        );
+        self.publisher
+            .publish_retained("$description", description.to_string())
+            .await
+    }
+
+    async fn set_state(&mut self, state: State) -> Result<(), Error> {
         self.state = state;
         self.publisher.publish_retained("$state", self.state).await
     }
 
+    /// Return an error if the device is not currently in one of the given states.
+    fn check_state(&self, allowed: &[State], to: State) -> Result<(), Error> {
+        if allowed.contains(&self.state) {
+            Ok(())
+        } else {
+            Err(Error::InvalidStateTransition {
+                from: self.state,
+                to,
+            })
+        }
+    }
+
     /// Update the [state](https://homieiot.github.io/specification/#device-lifecycle) of the Homie
     /// device to 'ready'. This should be called once it is ready to begin normal operation, or to
     /// return to normal operation after calling `sleep()` or `alert()`.
-    pub async fn ready(&mut self) -> Result<(), SendError<Request>> {
-        assert!(&[State::Init, State::Sleeping, State::Alert].contains(&self.state));
-        self.set_state(State::Ready).await
+    pub async fn ready(&mut self) -> Result<(), Error> {
+        self.check_state(&[State::Init, State::Sleeping, State::Alert], State::Ready)?;
+        self.set_state(State::Ready).await?;
+        Ok(())
     }
 
     /// Update the [state](https://homieiot.github.io/specification/#device-lifecycle) of the Homie
-    /// device to 'sleeping'. This should be only be called after `ready()`, otherwise it will panic.
-    pub async fn sleep(&mut self) -> Result<(), SendError<Request>> {
-        assert_eq!(self.state, State::Ready);
-        self.set_state(State::Sleeping).await
+    /// device to 'sleeping'. This should be only be called after `ready()`, otherwise it will
+    /// return an error.
+    pub async fn sleep(&mut self) -> Result<(), Error> {
+        self.check_state(&[State::Ready], State::Sleeping)?;
+        self.set_state(State::Sleeping).await?;
+        Ok(())
     }
 
     /// Update the [state](https://homieiot.github.io/specification/#device-lifecycle) of the Homie
     /// device to 'alert', to indicate that something wrong is happening and manual intervention may
-    /// be required. This should be only be called after `ready()`, otherwise it will panic.
-    pub async fn alert(&mut self) -> Result<(), SendError<Request>> {
-        assert_eq!(self.state, State::Ready);
-        self.set_state(State::Alert).await
+    /// be required. This should be only be called after `ready()`, otherwise it will return an
+    /// error.
+    ///
+    /// Homie 5 has no alert state, so this will always return an error for a Homie 5 device.
+    pub async fn alert(&mut self) -> Result<(), Error> {
+        let allowed: &[State] = if self.homie_version == HomieVersion::V5 {
+            &[]
+        } else {
+            &[State::Ready]
+        };
+        self.check_state(allowed, State::Alert)?;
+        self.set_state(State::Alert).await?;
+        Ok(())
+    }
+
+    /// Get the number of messages which are waiting to be sent to the MQTT broker. This grows while
+    /// the broker is unreachable or slow, so can be used to apply back-pressure before publishing
+    /// blocks.
+    pub fn queue_depth(&self) -> usize {
+        self.publisher.queue_depth()
     }
 
     /// Disconnect cleanly from the MQTT broker, after updating the state of the Homie device to
-    // 'disconnected'.
-    pub async fn disconnect(mut self) -> Result<(), SendError<Request>> {
+    /// 'disconnected'.
+    ///
+    /// If this device made the MQTT connection then any other devices which were added to it are
+    /// also marked as disconnected, and the connection is closed. Otherwise the connection is left
+    /// open for the other devices.
+    pub async fn disconnect(mut self) -> Result<(), Error> {
         self.set_state(State::Disconnected).await?;
-        self.publisher.disconnect().await
+        let others = {
+            let mut devices = self.devices.lock().unwrap();
+            if self.owns_connection {
+                devices.drain(..).map(|(publisher, _)| publisher).collect()
+            } else {
+                devices
+                    .retain(|(publisher, _)| publisher.device_base != self.publisher.device_base);
+                vec![]
+            }
+        };
+        for publisher in others {
+            if publisher.device_base != self.publisher.device_base {
+                publisher
+                    .publish_retained("$state", State::Disconnected)
+                    .await?;
+            }
+        }
+        if self.owns_connection {
+            self.publisher.disconnect().await?;
+        }
+        Ok(())
     }
 
-    /// Publish a new value for the given property of the given node of this device. The caller is
-    /// responsible for ensuring that the value is of the correct type.
+    /// Publish a new value for the given property of the given node of this device. It is retained
+    /// and published with the QoS level which were set for the property.
+    ///
+    /// This will return an error without publishing anything if there is no such property, or the
+    /// value doesn't match the property's datatype and format.
     pub async fn publish_value(
         &self,
         node_id: &str,
         property_id: &str,
-        value: impl ToString,
-    ) -> Result<(), SendError<Request>> {
+        value: impl Into<Value>,
+    ) -> Result<(), Error> {
+        let value = value.into();
+        let property = find_property(&self.nodes, node_id, property_id).ok_or_else(|| {
+            Error::UnknownProperty {
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+            }
+        })?;
+        property
+            .validate_value(&value)
+            .map_err(|reason| Error::InvalidValue {
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+                reason,
+            })?;
         self.publisher
-            .publish_retained(&format!("{}/{}", node_id, property_id), value.to_string())
-            .await
+            .publish_value(&format!("{}/{}", node_id, property_id), &property, value)
+            .await?;
+        Ok(())
     }
-}
 
-#[derive(Clone, Debug)]
-struct DevicePublisher {
-    requests_tx: Sender<Request>,
-    device_base: String,
+    /// Publish a new value for the given property of the element with the given index of the
+    /// given node array.
+    ///
+    /// This will return an error without publishing anything if the node is not an array, the
+    /// index is outside its range, there is no such property, or the value doesn't match the
+    /// property's datatype and format.
+    pub async fn publish_value_at(
+        &self,
+        node_id: &str,
+        index: u32,
+        property_id: &str,
+        value: impl Into<Value>,
+    ) -> Result<(), Error> {
+        let element_id = {
+            let nodes = self.nodes.lock().unwrap();
+            let node = nodes
+                .iter()
+                .find(|node| node.id == node_id)
+                .ok_or_else(|| Error::UnknownNode {
+                    node_id: node_id.to_owned(),
+                })?;
+            node.check_index(index)?;
+            node.element_id(index)
+        };
+        self.publish_value(&element_id, property_id, value).await
+    }
+}
+
+impl Drop for HomieDevice {
+    fn drop(&mut self) {
+        let was_connected = {
+            let mut devices = self.devices.lock().unwrap();
+            let count = devices.len();
+            devices.retain(|(publisher, _)| publisher.device_base != self.publisher.device_base);
+            devices.len() != count
+        };
+        // The last will only covers the device which made the connection, so let controllers know
+        // that any other device has gone if it wasn't disconnected cleanly. This is best effort, as
+        // we can't wait here.
+        if was_connected && !self.owns_connection && self.state != State::Disconnected {
+            let mut publish = Publish::new(
+                format!("{}/$state", self.publisher.device_base),
+                QoS::AtLeastOnce,
+                State::Lost,
+            );
+            publish.set_retain(true);
+            if self.publisher.requests_tx.try_send(publish.into()).is_err() {
+                log::warn!(
+                    "Couldn't mark dropped device {} as lost",
+                    self.publisher.device_base
+                );
+            }
+        }
+    }
+}
+
+/// Log a timeout from a background task rather than stopping it, as the broker may just be slow
+/// and the next attempt may succeed. Other errors are returned.
+pub(crate) fn ignore_timeout(result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+        Err(Error::Timeout { timeout }) => {
+            log::warn!("Timed out after {:?} publishing, skipping", timeout);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Find the device which the given topic is for, and return it along with the rest of the topic
+/// after the device base.
+fn find_device<'a>(
+    devices: &SharedDevices,
+    topic: &'a str,
+) -> Option<(DevicePublisher, SharedNodes, &'a str)> {
+    devices
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|(publisher, nodes)| {
+            let rest = topic
+                .strip_prefix(publisher.device_base.as_str())?
+                .strip_prefix('/')?;
+            Some((publisher.clone(), nodes.clone(), rest))
+        })
+}
+
+/// Handle a request from the Homie controller to set the given property to the given value, by
+/// passing it to the property's set handler and publishing the result.
+async fn handle_set(
+    publisher: &DevicePublisher,
+    nodes: &Mutex<Vec<Node>>,
+    node_id: &str,
+    property_id: &str,
+    payload: &str,
+) -> Result<(), Error> {
+    log::trace!(
+        "set node {:?} property {:?} to {:?}",
+        node_id,
+        property_id,
+        payload
+    );
+    let property = match find_property(nodes, node_id, property_id) {
+        Some(property) => property,
+        None => {
+            log::warn!("Set for unknown property {}/{}", node_id, property_id);
+            return Ok(());
+        }
+    };
+    let set_handler = match &property.set_handler {
+        Some(set_handler) => set_handler,
+        None => {
+            log::warn!("No set handler for property {}/{}", node_id, property_id);
+            return Ok(());
+        }
+    };
+    let value = match property.parse_value(payload) {
+        Ok(value) => value,
+        Err(reason) => {
+            log::warn!(
+                "Ignoring invalid value {:?} for {}/{}: {}",
+                payload,
+                node_id,
+                property_id,
+                reason
+            );
+            return Ok(());
+        }
+    };
+    let target_subtopic = format!("{}/{}/$target", node_id, property_id);
+    if property.target {
+        publisher
+            .publish_retained(&target_subtopic, value.to_string())
+            .await?;
+    }
+    match set_handler(value).await {
+        Ok(Some(value)) => {
+            if let Err(reason) = property.validate_value(&value) {
+                log::error!(
+                    "Set handler returned invalid value {:?} for {}/{}: {}",
+                    value,
+                    node_id,
+                    property_id,
+                    reason
+                );
+            } else {
+                publisher
+                    .publish_value(&format!("{}/{}", node_id, property_id), &property, value)
+                    .await?;
+            }
+        }
+        Ok(None) => {}
+        Err(reason) => {
+            log::warn!(
+                "Set handler rejected {:?} for {}/{}: {}",
+                payload,
+                node_id,
+                property_id,
+                reason
+            );
+            if property.target {
+                publisher.clear_retained(&target_subtopic).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the property with the given ID in the node with the given topic ID, if there is one. The
+/// node ID may be for an element of a node array.
+fn find_property(nodes: &Mutex<Vec<Node>>, node_id: &str, property_id: &str) -> Option<Property> {
+    nodes
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|node| node.has_topic_id(node_id))?
+        .properties
+        .iter()
+        .find(|property| property.id == property_id)
+        .cloned()
+}
+
+/// All the retained subtopics which the given node may publish, whether or not they are set.
+fn node_subtopics(node: &Node) -> Vec<String> {
+    let mut subtopics = vec![
+        format!("{}/$name", node.id),
+        format!("{}/$type", node.id),
+        format!("{}/$properties", node.id),
+        format!("{}/$array", node.id),
+    ];
+    if node.array.is_some() {
+        for (element_id, _) in node.instances() {
+            subtopics.push(format!("{}/$name", element_id));
+            for property in &node.properties {
+                subtopics.push(format!("{}/{}", element_id, property.id));
+                subtopics.push(format!("{}/{}/$target", element_id, property.id));
+            }
+        }
+    }
+    for property in &node.properties {
+        for attribute in &[
+            "",
+            "/$name",
+            "/$datatype",
+            "/$settable",
+            "/$retained",
+            "/$unit",
+            "/$format",
+            "/$target",
+        ] {
+            subtopics.push(format!("{}/{}{}", node.id, property.id, attribute));
+        }
+    }
+    subtopics
+}
+
+#[derive(Clone, Debug)]
+struct DevicePublisher {
+    requests_tx: Sender<Request>,
+    device_base: String,
+    published: Arc<Mutex<PublishedTopics>>,
+    /// The outbox which `requests_tx` sends to, if any.
+    outbox: Option<Outbox>,
+    publish_timeout: Option<Duration>,
+}
+
+/// Everything which has been published or subscribed to, so that it can be restored if the
+/// connection to the MQTT broker is lost and re-established.
+#[derive(Debug, Default)]
+struct PublishedTopics {
+    /// The latest retained message for each subtopic.
+    retained: BTreeMap<String, Vec<u8>>,
+    /// The latest retained message for each topic outside the device base, keyed by full topic.
+    other_retained: BTreeMap<String, Vec<u8>>,
+    /// The full topics we are subscribed to.
+    subscriptions: BTreeSet<String>,
+    /// Whether `disconnect` has been called, in which case we shouldn't try to reconnect.
+    disconnecting: bool,
 }
 
 impl DevicePublisher {
@@ -559,6 +1578,41 @@ impl DevicePublisher {
         Self {
             requests_tx,
             device_base,
+            published: Default::default(),
+            outbox: None,
+            publish_timeout: None,
+        }
+    }
+
+    /// Create a publisher for another device which shares the same MQTT connection.
+    fn for_device(&self, device_base: String) -> Self {
+        Self {
+            requests_tx: self.requests_tx.clone(),
+            device_base,
+            published: Default::default(),
+            outbox: self.outbox.clone(),
+            publish_timeout: self.publish_timeout,
+        }
+    }
+
+    /// Send a request to the MQTT event loop, giving up if it takes longer than the publish
+    /// timeout.
+    async fn send(&self, request: Request) -> Result<(), Error> {
+        match self.publish_timeout {
+            Some(publish_timeout) => timeout(publish_timeout, self.requests_tx.send(request))
+                .await
+                .map_err(|_| Error::Timeout {
+                    timeout: publish_timeout,
+                })??,
+            None => self.requests_tx.send(request).await?,
+        }
+        Ok(())
+    }
+
+    fn queue_depth(&self) -> usize {
+        match &self.outbox {
+            Some(outbox) => outbox.len(),
+            None => self.requests_tx.len(),
         }
     }
 
@@ -566,67 +1620,176 @@ impl DevicePublisher {
         &self,
         subtopic: &str,
         value: impl Into<Vec<u8>>,
-    ) -> Result<(), SendError<Request>> {
-        let topic = format!("{}/{}", self.device_base, subtopic);
+    ) -> Result<(), Error> {
+        let value = value.into();
+        self.published
+            .lock()
+            .unwrap()
+            .retained
+            .insert(subtopic.to_owned(), value.clone());
+        self.send_retained(subtopic, value).await
+    }
+
+    /// Publish a value for the given property, retained and with the QoS level of the property.
+    async fn publish_value(
+        &self,
+        subtopic: &str,
+        property: &Property,
+        value: Value,
+    ) -> Result<(), Error> {
+        let value = value.to_string().into_bytes();
+        if property.retained {
+            self.published
+                .lock()
+                .unwrap()
+                .retained
+                .insert(subtopic.to_owned(), value.clone());
+        }
+        let mut publish = Publish::new(
+            format!("{}/{}", self.device_base, subtopic),
+            property.qos,
+            value,
+        );
+        publish.set_retain(property.retained);
+        self.send(publish.into()).await
+    }
+
+    async fn send_retained(&self, subtopic: &str, value: Vec<u8>) -> Result<(), Error> {
+        self.send_retained_topic(&format!("{}/{}", self.device_base, subtopic), value)
+            .await
+    }
+
+    /// Publish a retained message to the given topic, which is not relative to the device base.
+    async fn publish_retained_topic(
+        &self,
+        topic: &str,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let value = value.into();
+        self.published
+            .lock()
+            .unwrap()
+            .other_retained
+            .insert(topic.to_owned(), value.clone());
+        self.send_retained_topic(topic, value).await
+    }
+
+    async fn send_retained_topic(&self, topic: &str, value: Vec<u8>) -> Result<(), Error> {
         let mut publish = Publish::new(topic, QoS::AtLeastOnce, value);
         publish.set_retain(true);
-        self.requests_tx.send(publish.into()).await
+        self.send(publish.into()).await
     }
 
-    async fn disconnect(&self) -> Result<(), SendError<Request>> {
-        self.requests_tx.send(Request::Disconnect).await
+    async fn clear_retained_topic(&self, topic: &str) -> Result<(), Error> {
+        self.published.lock().unwrap().other_retained.remove(topic);
+        self.send_retained_topic(topic, vec![]).await
     }
 
-    async fn subscribe(&self, subtopic: &str) -> Result<(), SendError<Request>> {
-        let topic = format!("{}/{}", self.device_base, subtopic);
-        let subscribe = Subscribe::new(topic, QoS::AtLeastOnce);
-        self.requests_tx.send(subscribe.into()).await
+    /// Clear the retained message for the given subtopic, and forget it so that it isn't
+    /// re-published after reconnecting.
+    async fn clear_retained(&self, subtopic: &str) -> Result<(), Error> {
+        self.published.lock().unwrap().retained.remove(subtopic);
+        self.send_retained(subtopic, vec![]).await
     }
 
-    async fn unsubscribe(&self, subtopic: &str) -> Result<(), SendError<Request>> {
-        let topic = format!("{}/{}", self.device_base, subtopic);
-        let unsubscribe = Unsubscribe::new(topic);
-        self.requests_tx.send(unsubscribe.into()).await
+    /// Get all subtopics with retained messages which start with the given prefix.
+    fn retained_subtopics(&self, prefix: &str) -> Vec<String> {
+        self.published
+            .lock()
+            .unwrap()
+            .retained
+            .keys()
+            .filter(|subtopic| subtopic.starts_with(prefix))
+            .cloned()
+            .collect()
     }
-}
 
-/// Legacy stats extension.
-#[derive(Debug)]
-struct HomieStats {
-    publisher: DevicePublisher,
-    start_time: Instant,
-}
-
-impl HomieStats {
-    const EXTENSION_ID: &'static str = "org.homie.legacy-stats:0.1.1:[4.x]";
+    async fn disconnect(&self) -> Result<(), Error> {
+        self.published.lock().unwrap().disconnecting = true;
+        self.send(Request::Disconnect).await
+    }
 
-    fn new(publisher: DevicePublisher) -> Self {
-        let now = Instant::now();
-        Self {
-            publisher,
-            start_time: now,
+    /// The Homie base topic which the device is under, e.g. "homie".
+    fn homie_base(&self) -> &str {
+        match self.device_base.rfind('/') {
+            Some(index) => &self.device_base[..index],
+            None => "",
         }
     }
 
-    /// Send initial topics.
-    async fn start(&self) -> Result<(), SendError<Request>> {
-        self.publisher
-            .publish_retained("$stats/interval", STATS_INTERVAL.as_secs().to_string())
+    fn is_disconnecting(&self) -> bool {
+        self.published.lock().unwrap().disconnecting
+    }
+
+    async fn subscribe(&self, subtopic: &str) -> Result<(), Error> {
+        self.subscribe_topic(&format!("{}/{}", self.device_base, subtopic))
             .await
     }
 
-    /// Periodically send stats.
-    fn spawn(self) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> {
-        let task: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> = task::spawn(async move {
-            loop {
-                let uptime = Instant::now() - self.start_time;
-                self.publisher
-                    .publish_retained("$stats/uptime", uptime.as_secs().to_string())
-                    .await?;
-                delay_for(STATS_INTERVAL).await;
+    /// Subscribe to the given topic, which is not relative to the device base.
+    async fn subscribe_topic(&self, topic: &str) -> Result<(), Error> {
+        self.published
+            .lock()
+            .unwrap()
+            .subscriptions
+            .insert(topic.to_owned());
+        self.send_subscribe(topic).await
+    }
+
+    async fn send_subscribe(&self, topic: &str) -> Result<(), Error> {
+        let subscribe = Subscribe::new(topic, QoS::AtLeastOnce);
+        self.send(subscribe.into()).await
+    }
+
+    async fn unsubscribe(&self, subtopic: &str) -> Result<(), Error> {
+        let topic = format!("{}/{}", self.device_base, subtopic);
+        self.published.lock().unwrap().subscriptions.remove(&topic);
+        let unsubscribe = Unsubscribe::new(topic);
+        self.send(unsubscribe.into()).await
+    }
+
+    /// Re-publish all retained attributes and re-subscribe to all topics which were previously
+    /// subscribed to, after reconnecting to the MQTT broker. Property values are only re-published
+    /// if `include_values` is true.
+    ///
+    /// The device is in the `init` state while this is happening, and then returns to its previous
+    /// state.
+    async fn republish(&self, include_values: bool) -> Result<(), Error> {
+        let (retained, other_retained, subscriptions) = {
+            let published = self.published.lock().unwrap();
+            (
+                published.retained.clone(),
+                published.other_retained.clone(),
+                published.subscriptions.clone(),
+            )
+        };
+        self.send_retained("$state", State::Init.into()).await?;
+        for (subtopic, value) in retained {
+            // Attribute topics all have a `$` somewhere, property values never do.
+            let is_value = !subtopic.contains('$');
+            if subtopic != "$state" && (include_values || !is_value) {
+                self.send_retained(&subtopic, value).await?;
             }
-        });
-        task.map(|res| Ok(res??))
+        }
+        for (topic, value) in other_retained {
+            self.send_retained_topic(&topic, value).await?;
+        }
+        for topic in subscriptions {
+            self.send_subscribe(&topic).await?;
+        }
+        // Look this up again rather than using the copy from above, in case it has changed in the
+        // meantime.
+        let state = self
+            .published
+            .lock()
+            .unwrap()
+            .retained
+            .get("$state")
+            .cloned();
+        if let Some(state) = state {
+            self.send_retained("$state", state).await?;
+        }
+        Ok(())
     }
 }
 
@@ -650,12 +1813,22 @@ impl HomieFirmware {
     }
 
     /// Send initial topics.
-    async fn start(&self) -> Result<(), SendError<Request>> {
+    async fn start(&self) -> Result<(), Error> {
+        let local_ip = local_ipaddress::get().ok_or_else(|| Error::NetworkLookup {
+            reason: "Couldn't find local IP address".to_string(),
+        })?;
+        let mac_address = get_mac_address()
+            .map_err(|e| Error::NetworkLookup {
+                reason: e.to_string(),
+            })?
+            .ok_or_else(|| Error::NetworkLookup {
+                reason: "Couldn't find a MAC address".to_string(),
+            })?;
         self.publisher
-            .publish_retained("$localip", local_ipaddress::get().unwrap())
+            .publish_retained("$localip", local_ip)
             .await?;
         self.publisher
-            .publish_retained("$mac", get_mac_address().unwrap().unwrap().to_string())
+            .publish_retained("$mac", mac_address.to_string())
             .await?;
         self.publisher
             .publish_retained("$fw/name", self.firmware_name.as_str())
@@ -705,36 +1878,61 @@ mod tests {
     }
 
     #[tokio::test]
-    #[should_panic(expected = "Tried to add node with duplicate ID")]
     async fn add_node_fails_given_duplicate_id() {
         let (mut device, rx) = make_test_device();
 
         device
-            .add_node(Node::new("id", "Name", "type", vec![]))
-            .await
-            .unwrap();
-        device
-            .add_node(Node::new("id", "Name 2", "type2", vec![]))
+            .add_node(Node::new("id", "Name", "type", vec![]).unwrap())
             .await
             .unwrap();
+        let result = device
+            .add_node(Node::new("id", "Name 2", "type2", vec![]).unwrap())
+            .await;
+        assert!(matches!(result, Err(Error::DuplicateNode { node_id }) if node_id == "id"));
+
+        // Need to keep rx alive until here so that the channel isn't closed.
+        drop(rx);
+    }
+
+    #[test]
+    fn new_fails_given_invalid_id() {
+        let result = Node::new("Bad_ID", "Name", "type", vec![]);
+        assert!(matches!(result, Err(Error::InvalidId { id }) if id == "Bad_ID"));
+
+        let result = Property::new("-prop", "Prop", Datatype::Integer, false, None, None);
+        assert!(matches!(result, Err(Error::InvalidId { id }) if id == "-prop"));
+    }
+
+    #[tokio::test]
+    async fn remove_node_fails_given_unknown_id() {
+        let (mut device, rx) = make_test_device();
+
+        let result = device.remove_node("id").await;
+        assert!(matches!(result, Err(Error::UnknownNode { node_id }) if node_id == "id"));
 
         // Need to keep rx alive until here so that the channel isn't closed.
         drop(rx);
     }
 
     #[tokio::test]
-    #[should_panic(expected = "Init")]
     async fn ready_fails_if_called_before_start() {
         let (mut device, rx) = make_test_device();
 
-        device.ready().await.unwrap();
+        let result = device.ready().await;
+        assert!(matches!(
+            result,
+            Err(Error::InvalidStateTransition {
+                from: State::Disconnected,
+                to: State::Ready
+            })
+        ));
 
         // Need to keep rx alive until here so that the channel isn't closed.
         drop(rx);
     }
 
     #[tokio::test]
-    async fn start_succeeds_with_no_nodes() -> Result<(), SendError<Request>> {
+    async fn start_succeeds_with_no_nodes() -> Result<(), Error> {
         let (mut device, rx) = make_test_device();
 
         device.start().await?;
@@ -746,7 +1944,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn sleep_then_ready_again_succeeds() -> Result<(), SendError<Request>> {
+    async fn sleep_then_ready_again_succeeds() -> Result<(), Error> {
         let (mut device, rx) = make_test_device();
 
         device.start().await?;
@@ -760,7 +1958,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn alert_then_ready_again_succeeds() -> Result<(), SendError<Request>> {
+    async fn alert_then_ready_again_succeeds() -> Result<(), Error> {
         let (mut device, rx) = make_test_device();
 
         device.start().await?;
@@ -774,7 +1972,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn disconnect_succeeds_before_ready() -> Result<(), SendError<Request>> {
+    async fn disconnect_succeeds_before_ready() -> Result<(), Error> {
         let (mut device, rx) = make_test_device();
 
         device.start().await?;
@@ -786,7 +1984,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn disconnect_succeeds_after_ready() -> Result<(), SendError<Request>> {
+    async fn disconnect_succeeds_after_ready() -> Result<(), Error> {
         let (mut device, rx) = make_test_device();
 
         device.start().await?;
@@ -799,14 +1997,93 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn minimal_build_succeeds() -> Result<(), SendError<Request>> {
+    async fn start_publishes_homie_3_attributes() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        device.homie_version = HomieVersion::V3;
+
+        device.start().await?;
+
+        let (publishes, _) = drain_requests(&rx);
+        assert!(publishes.contains(&("homie/test-device/$homie".to_string(), "3.0.1".to_string())));
+        assert!(!publishes
+            .iter()
+            .any(|(topic, _)| topic == "homie/test-device/$extensions"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn homie_5_publishes_description() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        device.homie_version = HomieVersion::V5;
+
+        device.start().await?;
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![Property::new(
+                    "on",
+                    "On",
+                    Datatype::Boolean,
+                    true,
+                    None,
+                    None,
+                )?],
+            )?)
+            .await?;
+        device.ready().await?;
+        assert!(matches!(
+            device.alert().await,
+            Err(Error::InvalidStateTransition { .. })
+        ));
+        device.remove_node("node").await?;
+
+        let (publishes, subscriptions) = drain_requests(&rx);
+        assert_eq!(subscriptions, vec!["homie/test-device/node/on/set"]);
+        assert!(!publishes
+            .iter()
+            .any(|(topic, _)| topic == "homie/test-device/$homie"
+                || topic == "homie/test-device/$nodes"
+                || topic == "homie/test-device/node/$properties"));
+        let descriptions = publishes
+            .iter()
+            .filter(|(topic, _)| topic == "homie/test-device/$description")
+            .map(|(_, payload)| serde_json::from_str(payload).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(descriptions.len(), 3);
+        assert_eq!(descriptions[0]["nodes"], serde_json::json!({}));
+        assert_eq!(
+            descriptions[1]["nodes"]["node"]["properties"]["on"]["settable"],
+            true
+        );
+        assert_eq!(descriptions[2]["nodes"], serde_json::json!({}));
+        assert_ne!(descriptions[0]["version"], descriptions[1]["version"]);
+        assert_ne!(descriptions[1]["version"], descriptions[2]["version"]);
+        Ok(())
+    }
+
+    #[test]
+    fn homie_5_device_base() {
+        assert_eq!(
+            HomieVersion::V5.device_base("homie/my-device"),
+            "homie/5/my-device"
+        );
+        assert_eq!(
+            HomieVersion::V4.device_base("homie/my-device"),
+            "homie/my-device"
+        );
+    }
+
+    #[tokio::test]
+    async fn minimal_build_succeeds() -> Result<(), Error> {
         let builder = HomieDevice::builder(
             "homie/test-device",
             "Test device",
             MqttOptions::new("client_id", "hostname", 1234),
         );
 
-        let (_event_loop, homie, _stats, firmware, _callback) = builder.build();
+        let (_event_loop, homie, _stats, firmware, _broadcast_callback) = builder.build();
 
         assert_eq!(homie.device_name, "Test device");
         assert_eq!(homie.publisher.device_base, "homie/test-device");
@@ -817,7 +2094,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn set_firmware_build_succeeds() -> Result<(), SendError<Request>> {
+    async fn set_firmware_build_succeeds() -> Result<(), Error> {
         let mut builder = HomieDevice::builder(
             "homie/test-device",
             "Test device",
@@ -826,7 +2103,7 @@ mod tests {
 
         builder.set_firmware("firmware_name", "firmware_version");
 
-        let (_event_loop, homie, _stats, firmware, _callback) = builder.build();
+        let (_event_loop, homie, _stats, firmware, _broadcast_callback) = builder.build();
 
         assert_eq!(homie.device_name, "Test device");
         assert_eq!(homie.publisher.device_base, "homie/test-device");
@@ -837,11 +2114,11 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn add_node_succeeds_before_and_after_start() -> Result<(), SendError<Request>> {
+    async fn add_node_succeeds_before_and_after_start() -> Result<(), Error> {
         let (mut device, rx) = make_test_device();
 
         device
-            .add_node(Node::new("id", "Name", "type", vec![]))
+            .add_node(Node::new("id", "Name", "type", vec![])?)
             .await?;
 
         device.start().await?;
@@ -849,7 +2126,7 @@ mod tests {
 
         // Add another node after starting.
         device
-            .add_node(Node::new("id2", "Name 2", "type2", vec![]))
+            .add_node(Node::new("id2", "Name 2", "type2", vec![])?)
             .await?;
 
         // Need to keep rx alive until here so that the channel isn't closed.
@@ -859,22 +2136,736 @@ mod tests {
 
     /// Add a node, remove it, and add it back again.
     #[tokio::test]
-    async fn add_node_succeeds_after_remove() -> Result<(), SendError<Request>> {
+    async fn add_node_succeeds_after_remove() -> Result<(), Error> {
         let (mut device, rx) = make_test_device();
 
         device
-            .add_node(Node::new("id", "Name", "type", vec![]))
+            .add_node(Node::new("id", "Name", "type", vec![])?)
             .await?;
 
         device.remove_node("id").await?;
 
         // Adding it back shouldn't give an error.
         device
-            .add_node(Node::new("id", "Name", "type", vec![]))
+            .add_node(Node::new("id", "Name", "type", vec![])?)
             .await?;
 
         // Need to keep rx alive until here so that the channel isn't closed.
         drop(rx);
         Ok(())
     }
+
+    /// Collect the topics and payloads of all publishes which have been sent so far, and the topics
+    /// of all subscriptions.
+    fn drain_requests(rx: &Receiver<Request>) -> (Vec<(String, String)>, Vec<String>) {
+        let mut publishes = vec![];
+        let mut subscriptions = vec![];
+        while let Ok(request) = rx.try_recv() {
+            match request {
+                Request::Publish(publish) => publishes.push((
+                    publish.topic,
+                    String::from_utf8(publish.payload.to_vec()).unwrap(),
+                )),
+                Request::Subscribe(subscribe) => {
+                    subscriptions.extend(subscribe.topics.into_iter().map(|topic| topic.topic_path))
+                }
+                _ => {}
+            }
+        }
+        (publishes, subscriptions)
+    }
+
+    /// Records the requests sent by a device, and keeps track of the retained topic tree and
+    /// subscriptions which an MQTT broker would have as a result.
+    #[derive(Debug, Default)]
+    struct RecordingSink {
+        retained: BTreeMap<String, String>,
+        subscriptions: BTreeSet<String>,
+        disconnected: bool,
+    }
+
+    impl RecordingSink {
+        /// Apply all the requests which have been sent so far.
+        fn record(&mut self, rx: &Receiver<Request>) {
+            while let Ok(request) = rx.try_recv() {
+                match request {
+                    Request::Publish(publish) if publish.retain => {
+                        if publish.payload.is_empty() {
+                            self.retained.remove(&publish.topic);
+                        } else {
+                            let payload = String::from_utf8(publish.payload.to_vec()).unwrap();
+                            self.retained.insert(publish.topic, payload);
+                        }
+                    }
+                    Request::Subscribe(subscribe) => self
+                        .subscriptions
+                        .extend(subscribe.topics.into_iter().map(|topic| topic.topic_path)),
+                    Request::Unsubscribe(unsubscribe) => {
+                        for topic in unsubscribe.topics {
+                            self.subscriptions.remove(&topic);
+                        }
+                    }
+                    Request::Disconnect => self.disconnected = true,
+                    _ => {}
+                }
+            }
+        }
+
+        /// Assert that the retained topics under the test device, and their payloads, are exactly
+        /// the given ones.
+        fn assert_retained(&self, expected: &[(&str, &str)]) {
+            let expected = expected
+                .iter()
+                .map(|(subtopic, payload)| {
+                    (
+                        format!("homie/test-device/{}", subtopic),
+                        payload.to_string(),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>();
+            assert_eq!(self.retained, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn lifecycle_publishes_exact_topic_tree() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        let mut sink = RecordingSink::default();
+        let device_attributes = [
+            ("$homie", "4.0"),
+            ("$implementation", "homie-rs"),
+            ("$name", "Test device"),
+        ];
+
+        device.start().await?;
+        sink.record(&rx);
+        sink.assert_retained(&[device_attributes.as_ref(), &[("$state", "init")]].concat());
+
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![
+                    Property::new(
+                        "temperature",
+                        "Temperature",
+                        Datatype::Float,
+                        false,
+                        Some("ºC"),
+                        None,
+                    )?,
+                    Property::new("on", "On", Datatype::Boolean, true, None, None)?,
+                ],
+            )?)
+            .await?;
+        device.ready().await?;
+        device.publish_value("node", "temperature", 22.5).await?;
+        sink.record(&rx);
+        sink.assert_retained(
+            &[
+                device_attributes.as_ref(),
+                &[
+                    ("$state", "ready"),
+                    ("$nodes", "node"),
+                    ("node/$name", "Node"),
+                    ("node/$type", "type"),
+                    ("node/$properties", "temperature,on"),
+                    ("node/temperature", "22.5"),
+                    ("node/temperature/$name", "Temperature"),
+                    ("node/temperature/$datatype", "float"),
+                    ("node/temperature/$settable", "false"),
+                    ("node/temperature/$retained", "true"),
+                    ("node/temperature/$unit", "ºC"),
+                    ("node/on/$name", "On"),
+                    ("node/on/$datatype", "boolean"),
+                    ("node/on/$settable", "true"),
+                    ("node/on/$retained", "true"),
+                ],
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            sink.subscriptions.iter().collect::<Vec<_>>(),
+            vec!["homie/test-device/node/on/set"]
+        );
+
+        device.remove_node("node").await?;
+        sink.record(&rx);
+        sink.assert_retained(&[device_attributes.as_ref(), &[("$state", "ready")]].concat());
+        assert!(sink.subscriptions.is_empty());
+
+        device.disconnect().await?;
+        sink.record(&rx);
+        sink.assert_retained(&[device_attributes.as_ref(), &[("$state", "disconnected")]].concat());
+        assert!(sink.disconnected);
+        Ok(())
+    }
+
+    async fn make_started_device(
+        rx: &Receiver<Request>,
+        device: &mut HomieDevice,
+    ) -> Result<(), Error> {
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![
+                    Property::new(
+                        "temperature",
+                        "Temperature",
+                        Datatype::Float,
+                        false,
+                        None,
+                        None,
+                    )?,
+                    Property::new("on", "On", Datatype::Boolean, true, None, None)?,
+                ],
+            )?)
+            .await?;
+        device.start().await?;
+        device.ready().await?;
+        device.publish_value("node", "temperature", 22.5).await?;
+        drain_requests(rx);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn republish_restores_attributes_and_state() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        make_started_device(&rx, &mut device).await?;
+
+        device.publisher.republish(false).await?;
+
+        let (publishes, subscriptions) = drain_requests(&rx);
+        assert_eq!(
+            publishes.first(),
+            Some(&("homie/test-device/$state".to_string(), "init".to_string()))
+        );
+        assert_eq!(
+            publishes.last(),
+            Some(&("homie/test-device/$state".to_string(), "ready".to_string()))
+        );
+        assert!(publishes.contains(&("homie/test-device/$homie".to_string(), "4.0".to_string())));
+        assert!(publishes.contains(&(
+            "homie/test-device/node/on/$settable".to_string(),
+            "true".to_string()
+        )));
+        assert!(!publishes
+            .iter()
+            .any(|(topic, _)| topic == "homie/test-device/node/temperature"));
+        assert_eq!(subscriptions, vec!["homie/test-device/node/on/set"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn republish_includes_values_if_requested() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        make_started_device(&rx, &mut device).await?;
+
+        device.publisher.republish(true).await?;
+
+        let (publishes, _) = drain_requests(&rx);
+        assert!(publishes.contains(&(
+            "homie/test-device/node/temperature".to_string(),
+            "22.5".to_string()
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_node_clears_retained_topics() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        make_started_device(&rx, &mut device).await?;
+
+        device.remove_node("node").await?;
+
+        let (publishes, _) = drain_requests(&rx);
+        for topic in &[
+            "homie/test-device/node/$name",
+            "homie/test-device/node/$properties",
+            "homie/test-device/node/temperature",
+            "homie/test-device/node/temperature/$datatype",
+            "homie/test-device/node/on/$settable",
+        ] {
+            assert!(
+                publishes.contains(&(topic.to_string(), "".to_string())),
+                "{} not cleared",
+                topic
+            );
+        }
+        assert_eq!(
+            publishes.last(),
+            Some(&("homie/test-device/$nodes".to_string(), "".to_string()))
+        );
+
+        // Nothing from the node should be re-published after a reconnect.
+        device.publisher.republish(true).await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert!(!publishes
+            .iter()
+            .any(|(topic, _)| topic.starts_with("homie/test-device/node/")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_node_clears_retained_topics() -> Result<(), Error> {
+        let (device, rx) = make_test_device();
+
+        device
+            .purge_node(&Node::new(
+                "old",
+                "Old node",
+                "type",
+                vec![Property::new(
+                    "prop",
+                    "Property",
+                    Datatype::Integer,
+                    false,
+                    None,
+                    None,
+                )?],
+            )?)
+            .await?;
+
+        let (publishes, _) = drain_requests(&rx);
+        assert!(publishes.contains(&("homie/test-device/old/$type".to_string(), "".to_string())));
+        assert!(publishes.contains(&("homie/test-device/old/prop".to_string(), "".to_string())));
+        assert!(publishes.contains(&(
+            "homie/test-device/old/prop/$unit".to_string(),
+            "".to_string()
+        )));
+        assert!(publishes.iter().all(|(_, payload)| payload.is_empty()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_value_fails_for_unknown_property() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        make_started_device(&rx, &mut device).await?;
+
+        let result = device.publish_value("node", "humidity", 42).await;
+
+        assert!(matches!(result, Err(Error::UnknownProperty { .. })));
+        assert!(rx.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_value_fails_for_invalid_value() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        make_started_device(&rx, &mut device).await?;
+
+        let result = device.publish_value("node", "on", "maybe").await;
+
+        assert!(matches!(result, Err(Error::InvalidValue { .. })));
+        assert!(rx.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_value_honours_retained_and_qos() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        device.start().await?;
+        device
+            .add_node(Node::new(
+                "button",
+                "Button",
+                "type",
+                vec![
+                    Property::new("pressed", "Pressed", Datatype::Boolean, false, None, None)?
+                        .with_retained(false)
+                        .with_qos(QoS::AtMostOnce),
+                ],
+            )?)
+            .await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert!(publishes.contains(&(
+            "homie/test-device/button/pressed/$retained".to_string(),
+            "false".to_string()
+        )));
+
+        device.publish_value("button", "pressed", true).await?;
+
+        match rx.try_recv() {
+            Ok(Request::Publish(publish)) => {
+                assert_eq!(publish.topic, "homie/test-device/button/pressed");
+                assert!(!publish.retain);
+                assert_eq!(publish.qos, QoS::AtMostOnce);
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+        // Non-retained values shouldn't be re-published after a reconnect.
+        device.publisher.republish(true).await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert!(!publishes
+            .iter()
+            .any(|(topic, _)| topic == "homie/test-device/button/pressed"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn node_array_publishes_elements() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        device.homie_version = HomieVersion::V3;
+        device.start().await?;
+        device
+            .add_node(
+                Node::new(
+                    "sensors",
+                    "Sensor",
+                    "type",
+                    vec![Property::new(
+                        "temperature",
+                        "Temperature",
+                        Datatype::Float,
+                        false,
+                        None,
+                        None,
+                    )?],
+                )?
+                .with_array(1..=2)
+                .with_element_name(1, "Kitchen")?,
+            )
+            .await?;
+
+        device
+            .publish_value_at("sensors", 2, "temperature", 19.5)
+            .await?;
+        let result = device
+            .publish_value_at("sensors", 3, "temperature", 19.5)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::InvalidArrayIndex { index: 3, .. })
+        ));
+
+        let (publishes, _) = drain_requests(&rx);
+        for (topic, payload) in &[
+            ("homie/test-device/sensors/$array", "1-2"),
+            ("homie/test-device/sensors_1/$name", "Kitchen"),
+            ("homie/test-device/sensors_2/$name", "Sensor 2"),
+            ("homie/test-device/sensors_2/temperature", "19.5"),
+            ("homie/test-device/$nodes", "sensors[]"),
+        ] {
+            assert!(
+                publishes.contains(&(topic.to_string(), payload.to_string())),
+                "{} not published",
+                topic
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn node_array_fails_without_homie_3() -> Result<(), Error> {
+        for &homie_version in &[HomieVersion::V4, HomieVersion::V5] {
+            let (mut device, rx) = make_test_device();
+            device.homie_version = homie_version;
+            let result = device
+                .add_node(Node::new("sensors", "Sensor", "type", vec![])?.with_array(1..=2))
+                .await;
+            assert!(matches!(result, Err(Error::ArrayNotSupported { .. })));
+            assert!(rx.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn element_name_fails_for_invalid_index() -> Result<(), Error> {
+        let node = Node::new("node", "Node", "type", vec![])?;
+
+        let result = node.clone().with_element_name(0, "Zero");
+        assert!(matches!(
+            result,
+            Err(Error::InvalidArrayIndex { index: 0, .. })
+        ));
+        let result = node.with_array(1..=3).with_element_name(4, "Four");
+        assert!(matches!(
+            result,
+            Err(Error::InvalidArrayIndex { index: 4, .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_handler_result_is_published() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![Property::new(
+                    "level",
+                    "Level",
+                    Datatype::Integer,
+                    false,
+                    None,
+                    Some("0:10"),
+                )?
+                .with_set_handler(|value| async move {
+                    if value == Value::Integer(7) {
+                        Err("Unlucky".to_string())
+                    } else {
+                        Ok(Some(value))
+                    }
+                })],
+            )?)
+            .await?;
+        let (_, subscriptions) = drain_requests(&rx);
+        assert_eq!(subscriptions, vec!["homie/test-device/node/level/set"]);
+
+        handle_set(&device.publisher, &device.nodes, "node", "level", "5").await?;
+        // Rejected by the handler.
+        handle_set(&device.publisher, &device.nodes, "node", "level", "7").await?;
+        // Not valid for the format.
+        handle_set(&device.publisher, &device.nodes, "node", "level", "11").await?;
+        handle_set(&device.publisher, &device.nodes, "node", "other", "1").await?;
+
+        let (publishes, _) = drain_requests(&rx);
+        assert_eq!(
+            publishes,
+            vec![("homie/test-device/node/level".to_string(), "5".to_string())]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_publishes_target_before_value() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![
+                    Property::new("level", "Level", Datatype::Integer, false, None, None)?
+                        .with_target(true)
+                        .with_set_handler(|value| async move {
+                            match value {
+                                Value::Integer(level) if level < 0 => Err("Too low".to_string()),
+                                Value::Integer(level) if level > 10 => Ok(None),
+                                value => Ok(Some(value)),
+                            }
+                        }),
+                ],
+            )?)
+            .await?;
+        drain_requests(&rx);
+
+        handle_set(&device.publisher, &device.nodes, "node", "level", "5").await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert_eq!(
+            publishes,
+            vec![
+                (
+                    "homie/test-device/node/level/$target".to_string(),
+                    "5".to_string()
+                ),
+                ("homie/test-device/node/level".to_string(), "5".to_string()),
+            ]
+        );
+
+        // The handler will confirm the value later.
+        handle_set(&device.publisher, &device.nodes, "node", "level", "20").await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert_eq!(
+            publishes,
+            vec![(
+                "homie/test-device/node/level/$target".to_string(),
+                "20".to_string()
+            )]
+        );
+
+        // Rejected by the handler, so the target is cleared.
+        handle_set(&device.publisher, &device.nodes, "node", "level", "-1").await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert_eq!(
+            publishes,
+            vec![
+                (
+                    "homie/test-device/node/level/$target".to_string(),
+                    "-1".to_string()
+                ),
+                (
+                    "homie/test-device/node/level/$target".to_string(),
+                    "".to_string()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn homie_base_is_parent_of_device_base() {
+        let (requests_tx, _requests_rx) = async_channel::unbounded();
+        let publisher = DevicePublisher::new(requests_tx.clone(), "homie/device".to_string());
+        assert_eq!(publisher.homie_base(), "homie");
+        let publisher = DevicePublisher::new(requests_tx.clone(), "a/b/device".to_string());
+        assert_eq!(publisher.homie_base(), "a/b");
+        let publisher = DevicePublisher::new(requests_tx, "device".to_string());
+        assert_eq!(publisher.homie_base(), "");
+    }
+
+    #[tokio::test]
+    async fn home_assistant_config_is_published_and_removed() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        device.home_assistant = Some(HomeAssistantDiscovery::new(
+            "homeassistant",
+            "homie/test-device",
+            "Test device",
+        ));
+        make_started_device(&rx, &mut device).await?;
+
+        device.publisher.republish(false).await?;
+        let (publishes, _) = drain_requests(&rx);
+        let config_topic = "homeassistant/sensor/test-device/node_temperature/config".to_string();
+        assert!(publishes
+            .iter()
+            .any(|(topic, payload)| topic == &config_topic && !payload.is_empty()));
+
+        device.remove_node("node").await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert!(publishes.contains(&(config_topic, "".to_string())));
+        assert!(publishes.contains(&(
+            "homeassistant/switch/test-device/node_on/config".to_string(),
+            "".to_string()
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn added_device_has_its_own_state() -> Result<(), Error> {
+        let (mut device, rx) = make_test_device();
+        make_started_device(&rx, &mut device).await?;
+
+        let mut other = device.add_device("homie/other-device", "Other").await?;
+        other
+            .add_node(Node::new("node", "Node", "type", vec![])?)
+            .await?;
+        other.ready().await?;
+        let (publishes, _) = drain_requests(&rx);
+        assert!(publishes.contains(&("homie/other-device/$homie".to_string(), "4.0".to_string())));
+        assert!(publishes.contains(&("homie/other-device/$state".to_string(), "init".to_string())));
+        assert!(publishes.contains(&("homie/other-device/$nodes".to_string(), "node".to_string())));
+        assert_eq!(
+            publishes.last(),
+            Some(&("homie/other-device/$state".to_string(), "ready".to_string()))
+        );
+        assert!(!publishes
+            .iter()
+            .any(|(topic, _)| topic.starts_with("homie/test-device/")));
+
+        // Disconnecting the added device shouldn't close the connection.
+        other.disconnect().await?;
+        let mut disconnected = false;
+        while let Ok(request) = rx.try_recv() {
+            disconnected |= request == Request::Disconnect;
+        }
+        assert!(!disconnected);
+        assert_eq!(device.devices.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_device_fails_given_duplicate_id() -> Result<(), Error> {
+        let (device, rx) = make_test_device();
+
+        let _other = device.add_device("homie/other-device", "Other").await?;
+        let result = device.add_device("homie/other-device", "Other 2").await;
+        assert!(
+            matches!(result, Err(Error::DuplicateDevice { device_id }) if device_id == "other-device")
+        );
+        let result = device.add_device("homie/test-device", "Test 2").await;
+        assert!(matches!(result, Err(Error::DuplicateDevice { .. })));
+
+        drop(rx);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropped_device_is_marked_lost() -> Result<(), Error> {
+        let (device, rx) = make_test_device();
+        let other = device.add_device("homie/other-device", "Other").await?;
+        drain_requests(&rx);
+
+        drop(other);
+
+        let (publishes, _) = drain_requests(&rx);
+        assert_eq!(
+            publishes,
+            vec![("homie/other-device/$state".to_string(), "lost".to_string())]
+        );
+        assert_eq!(device.devices.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnect_marks_added_devices_disconnected() -> Result<(), Error> {
+        let (device, rx) = make_test_device();
+        let other = device.add_device("homie/other-device", "Other").await?;
+        drain_requests(&rx);
+
+        device.disconnect().await?;
+        drop(other);
+
+        let (publishes, _) = drain_requests(&rx);
+        assert_eq!(
+            publishes,
+            vec![
+                (
+                    "homie/test-device/$state".to_string(),
+                    "disconnected".to_string()
+                ),
+                (
+                    "homie/other-device/$state".to_string(),
+                    "disconnected".to_string()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_device_routes_by_device_base() -> Result<(), Error> {
+        let (device, rx) = make_test_device();
+        let _other = device.add_device("homie/test", "Other").await?;
+
+        let (publisher, _, rest) =
+            find_device(&device.devices, "homie/test/node/prop/set").unwrap();
+        assert_eq!(publisher.device_base, "homie/test");
+        assert_eq!(rest, "node/prop/set");
+        let (publisher, _, rest) =
+            find_device(&device.devices, "homie/test-device/node/prop/set").unwrap();
+        assert_eq!(publisher.device_base, "homie/test-device");
+        assert_eq!(rest, "node/prop/set");
+        assert!(find_device(&device.devices, "homie/unknown/node/prop/set").is_none());
+
+        drop(rx);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_times_out_when_queue_is_full() -> Result<(), Error> {
+        let (requests_tx, rx) = async_channel::bounded(1);
+        let mut publisher = DevicePublisher::new(requests_tx, "homie/test-device".to_string());
+        publisher.publish_timeout = Some(Duration::from_millis(10));
+
+        publisher.publish_retained("$name", "Test device").await?;
+        assert_eq!(publisher.queue_depth(), 1);
+        let result = publisher.publish_retained("$name", "Other name").await;
+        assert!(matches!(result, Err(Error::Timeout { .. })));
+        // Background tasks carry on after a timeout, but not once the event loop has gone.
+        assert!(ignore_timeout(result).is_ok());
+
+        drop(rx);
+        let result = publisher.publish_retained("$name", "Other name").await;
+        assert!(matches!(
+            ignore_timeout(result),
+            Err(Error::ChannelClosed(_))
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,362 @@
+//! A queue of requests on their way to the MQTT event loop, which holds them while the connection
+//! to the broker is down so that publishing doesn't block until it is full. Messages may also be
+//! spooled to disk while the connection is down.
+
+use crate::spool::Spool;
+use crate::REQUESTS_CAP;
+use async_channel::{Receiver, Sender};
+use futures::future::{self, Either};
+use futures::FutureExt;
+use rumqttc::Request;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Outbox {
+    state: Arc<Mutex<OutboxState>>,
+    /// The total number of requests which may be held while the connection is down, including
+    /// those waiting in the channel.
+    capacity: usize,
+    /// The maximum number of requests to hold in the queue, i.e. the capacity less that of the
+    /// channel.
+    queue_capacity: usize,
+    requests_rx: Receiver<Request>,
+    /// Used to wake up the forwarding task when the connection state changes.
+    wake_tx: Sender<()>,
+    wake_rx: Receiver<()>,
+}
+
+#[derive(Debug, Default)]
+struct OutboxState {
+    queue: VecDeque<Request>,
+    connected: bool,
+    /// Where to put messages while the connection is down, rather than the queue. This is only
+    /// used by the forwarding task, from blocking threads.
+    spool: Option<Arc<Mutex<Spool>>>,
+}
+
+impl Outbox {
+    /// Create a new outbox which can hold up to `capacity` requests while the connection is down,
+    /// and a sender for requests to it. Once it is full, sending blocks until the connection is
+    /// re-established.
+    pub(crate) fn new(capacity: usize) -> (Sender<Request>, Outbox) {
+        // Requests waiting in the channel count towards the capacity. The channel can't be
+        // zero-sized though, so the capacity is at least 1.
+        let channel_capacity = capacity.clamp(1, REQUESTS_CAP);
+        let (requests_tx, requests_rx) = async_channel::bounded(channel_capacity);
+        let (wake_tx, wake_rx) = async_channel::bounded(1);
+        let outbox = Outbox {
+            state: Default::default(),
+            capacity: capacity.max(1),
+            queue_capacity: capacity.saturating_sub(channel_capacity),
+            requests_rx,
+            wake_tx,
+            wake_rx,
+        };
+        (requests_tx, outbox)
+    }
+
+    /// Spool messages to the given spool while the connection is down, rather than holding them
+    /// in memory. Anything already in the spool will be sent once the connection is established.
+    pub(crate) fn set_spool(&self, spool: Spool) {
+        self.state.lock().unwrap().spool = Some(Arc::new(Mutex::new(spool)));
+    }
+
+    /// The number of requests which have been sent to the outbox but not yet forwarded to the
+    /// MQTT event loop, not counting any which have been spooled.
+    pub(crate) fn len(&self) -> usize {
+        self.requests_rx.len() + self.state.lock().unwrap().queue.len()
+    }
+
+    /// Record whether the MQTT event loop is currently connected to the broker. Requests are only
+    /// forwarded while it is.
+    pub(crate) fn set_connected(&self, connected: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.connected != connected {
+            state.connected = connected;
+            // If there's already a wakeup pending then that's fine.
+            let _ = self.wake_tx.try_send(());
+        }
+    }
+
+    /// Forward requests to the MQTT event loop in order, until all senders have been dropped, a
+    /// disconnect request has been forwarded, or the event loop goes away.
+    pub(crate) async fn run(self, event_loop_tx: Sender<Request>) {
+        loop {
+            if !self.forward_spooled(&event_loop_tx).await {
+                return;
+            }
+            while let Some(request) = self.next_to_forward() {
+                let disconnect = matches!(request, Request::Disconnect);
+                if event_loop_tx.send(request).await.is_err() || disconnect {
+                    return;
+                }
+            }
+
+            let full = {
+                let state = self.state.lock().unwrap();
+                !state.connected && state.queue.len() >= self.queue_capacity
+            };
+            if full {
+                // Leave requests in the channel so that senders block until we can forward more.
+                let _ = self.wake_rx.recv().await;
+            } else {
+                match future::select(self.requests_rx.recv().boxed(), self.wake_rx.recv().boxed())
+                    .await
+                {
+                    Either::Left((Ok(request), _)) => self.push(request).await,
+                    Either::Left((Err(_), _)) => {
+                        // Send whatever we can before giving up.
+                        if !self.forward_spooled(&event_loop_tx).await {
+                            return;
+                        }
+                        while let Some(request) = self.next_to_forward() {
+                            if event_loop_tx.send(request).await.is_err() {
+                                break;
+                            }
+                        }
+                        return;
+                    }
+                    Either::Right(_) => {}
+                }
+            }
+        }
+    }
+
+    /// If connected, forward everything which was spooled while the connection was down, and then
+    /// clear the spool. Anything spooled was published before what's in the queue, so this must be
+    /// done first.
+    ///
+    /// Returns false if the event loop has gone away.
+    async fn forward_spooled(&self, event_loop_tx: &Sender<Request>) -> bool {
+        let spool = {
+            let state = self.state.lock().unwrap();
+            match &state.spool {
+                Some(spool) if state.connected && !spool.lock().unwrap().is_empty() => {
+                    spool.clone()
+                }
+                _ => return true,
+            }
+        };
+        let spooled = match with_spool(spool.clone(), |spool| spool.read_all()).await {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                log::error!("Failed to read spooled messages: {}", e);
+                return true;
+            }
+        };
+        for publish in spooled {
+            if event_loop_tx.send(publish.into()).await.is_err() {
+                // Leave them in the spool to send next time.
+                return false;
+            }
+        }
+        if let Err(e) = with_spool(spool, |spool| spool.clear()).await {
+            log::error!("Failed to clear spooled messages: {}", e);
+        }
+        true
+    }
+
+    fn next_to_forward(&self) -> Option<Request> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return None;
+        }
+        state.queue.pop_front()
+    }
+
+    async fn push(&self, request: Request) {
+        let spool = {
+            let state = self.state.lock().unwrap();
+            state.spool.clone().filter(|_| !state.connected)
+        };
+        if let (Some(spool), Request::Publish(publish)) = (spool, &request) {
+            let publish = publish.clone();
+            match with_spool(spool, move |spool| spool.append(&publish)).await {
+                Ok(()) => return,
+                Err(e) => log::error!("Failed to spool message, keeping it in memory: {}", e),
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            if let Request::Publish(publish) = &request {
+                // Only the latest retained message for each topic matters, so there's no point
+                // sending any older ones which are still waiting.
+                if publish.retain {
+                    state.queue.retain(|queued| {
+                        !matches!(queued, Request::Publish(queued)
+                            if queued.retain && queued.topic == publish.topic)
+                    });
+                }
+            }
+        }
+        state.queue.push_back(request);
+        if !state.connected && state.queue.len() == self.queue_capacity {
+            log::warn!(
+                "MQTT outbox is full with {} requests, publishing will block until the broker is reachable",
+                self.capacity
+            );
+        }
+    }
+}
+
+/// Run the given operation on the spool on a thread where blocking is allowed, as it does file I/O.
+async fn with_spool<T, F>(spool: Arc<Mutex<Spool>>, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Spool) -> io::Result<T> + Send + 'static,
+{
+    // This only fails if the operation panicked.
+    task::spawn_blocking(move || f(&mut spool.lock().unwrap()))
+        .await
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::{Publish, QoS};
+    use std::time::Duration;
+    use tokio::task;
+    use tokio::time::{delay_for, timeout};
+
+    /// How long `wait_until` waits before giving up.
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn publish(topic: &str, payload: &str, retain: bool) -> Request {
+        let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload);
+        publish.set_retain(retain);
+        publish.into()
+    }
+
+    fn payloads(rx: &Receiver<Request>) -> Vec<String> {
+        let mut payloads = vec![];
+        while let Ok(request) = rx.try_recv() {
+            if let Request::Publish(publish) = request {
+                payloads.push(String::from_utf8(publish.payload.to_vec()).unwrap());
+            }
+        }
+        payloads
+    }
+
+    /// Wait until the given condition is true, or panic if it takes too long.
+    async fn wait_until(condition: impl Fn() -> bool) {
+        let result = timeout(WAIT_TIMEOUT, async {
+            while !condition() {
+                delay_for(Duration::from_millis(1)).await;
+            }
+        })
+        .await;
+        if result.is_err() {
+            panic!("Timed out waiting for condition");
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesces_retained_publishes_while_offline() {
+        let (requests_tx, outbox) = Outbox::new(100);
+        let (event_loop_tx, event_loop_rx) = async_channel::unbounded();
+        task::spawn(outbox.clone().run(event_loop_tx));
+
+        requests_tx.send(publish("a", "1", true)).await.unwrap();
+        requests_tx.send(publish("b", "2", false)).await.unwrap();
+        requests_tx.send(publish("b", "3", false)).await.unwrap();
+        requests_tx.send(publish("a", "4", true)).await.unwrap();
+        wait_until(|| outbox.requests_rx.is_empty() && outbox.len() == 3).await;
+        assert!(event_loop_rx.is_empty());
+
+        outbox.set_connected(true);
+        wait_until(|| event_loop_rx.len() == 3).await;
+        assert_eq!(outbox.len(), 0);
+        assert_eq!(payloads(&event_loop_rx), vec!["2", "3", "4"]);
+
+        // Once connected, every publish is forwarded.
+        requests_tx.send(publish("a", "5", true)).await.unwrap();
+        requests_tx.send(publish("a", "6", true)).await.unwrap();
+        wait_until(|| event_loop_rx.len() == 2).await;
+        assert_eq!(payloads(&event_loop_rx), vec!["5", "6"]);
+    }
+
+    #[tokio::test]
+    async fn blocks_when_full() {
+        // Try capacities both smaller and larger than the channel.
+        for &capacity in &[2, REQUESTS_CAP + 2] {
+            let (requests_tx, outbox) = Outbox::new(capacity);
+            let (event_loop_tx, event_loop_rx) = async_channel::unbounded();
+            task::spawn(outbox.clone().run(event_loop_tx));
+
+            for i in 0..capacity {
+                requests_tx
+                    .send(publish("a", &i.to_string(), false))
+                    .await
+                    .unwrap();
+            }
+            let blocked = timeout(
+                Duration::from_millis(50),
+                requests_tx.send(publish("a", "blocked", false)),
+            )
+            .await;
+            assert!(blocked.is_err());
+            assert_eq!(outbox.len(), capacity);
+
+            outbox.set_connected(true);
+            wait_until(|| event_loop_rx.len() == capacity).await;
+            assert_eq!(outbox.len(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn spools_publishes_while_offline() {
+        let path = std::env::temp_dir().join(format!("homie-outbox-spool-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (requests_tx, outbox) = Outbox::new(100);
+        outbox.set_spool(Spool::new(path.clone(), 10_000));
+        let (event_loop_tx, event_loop_rx) = async_channel::unbounded();
+        task::spawn(outbox.clone().run(event_loop_tx));
+
+        requests_tx.send(publish("a", "1", true)).await.unwrap();
+        requests_tx.send(publish("b", "2", false)).await.unwrap();
+        requests_tx.send(publish("a", "3", true)).await.unwrap();
+        let spooled = || {
+            std::fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .count()
+        };
+        wait_until(|| spooled() == 3).await;
+        assert_eq!(outbox.len(), 0);
+
+        outbox.set_connected(true);
+        wait_until(|| event_loop_rx.len() == 2).await;
+        assert_eq!(payloads(&event_loop_rx), vec!["2", "3"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_spool_if_not_forwarded() {
+        let path = std::env::temp_dir().join(format!("homie-outbox-keep-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut spool = Spool::new(path.clone(), 10_000);
+        spool
+            .append(&Publish::new("a", QoS::AtLeastOnce, "1"))
+            .unwrap();
+        let (_requests_tx, outbox) = Outbox::new(100);
+        outbox.set_spool(spool);
+
+        // The event loop has gone away, so nothing can be forwarded.
+        let (event_loop_tx, _) = async_channel::unbounded();
+        outbox.set_connected(true);
+        timeout(WAIT_TIMEOUT, outbox.run(event_loop_tx))
+            .await
+            .unwrap();
+        let spool = Spool::new(path.clone(), 10_000);
+        assert_eq!(spool.read_all().unwrap().len(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+//! An append-only file of messages which were published while the MQTT broker was unreachable, so
+//! that they can be sent once it is back even if the process has been restarted in between.
+//!
+//! Each message is stored as a line of JSON. The file has a maximum size, and once it is reached
+//! superseded retained messages are dropped, followed by the oldest messages until it is down to
+//! half of the maximum size, so that it doesn't need rewriting for every new message.
+//!
+//! All of these operations do blocking file I/O, so shouldn't be called directly from async code.
+
+use rumqttc::{Publish, QoS};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub(crate) struct Spool {
+    path: PathBuf,
+    max_size: u64,
+    /// The current size of the file.
+    size: u64,
+}
+
+impl Spool {
+    /// Use the file at the given path as a spool. Anything already in it from a previous run will
+    /// be kept, except for a partial record at the end.
+    pub(crate) fn new(path: PathBuf, max_size: u64) -> Spool {
+        let size = discard_partial_record(&path).unwrap_or_else(|e| {
+            log::error!("Failed to check spool {}: {}", path.display(), e);
+            fs::metadata(&path).map_or(0, |metadata| metadata.len())
+        });
+        Spool {
+            path,
+            max_size,
+            size,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Append the given message to the spool, making room for it if necessary.
+    pub(crate) fn append(&mut self, publish: &Publish) -> io::Result<()> {
+        let record = encode(publish);
+        let length = record.len() as u64;
+        if length > self.max_size {
+            log::warn!(
+                "Not spooling message to {} as it is too large",
+                publish.topic
+            );
+            return Ok(());
+        }
+        if self.size + length > self.max_size {
+            self.compact(length)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if let Err(e) = file.write_all(&record) {
+            // Don't leave part of the record behind for the next one to be appended to.
+            let _ = file.set_len(self.size);
+            return Err(e);
+        }
+        self.size += length;
+        Ok(())
+    }
+
+    /// Return all messages in the spool in order, with only the latest retained message for each
+    /// topic. They are left in the spool until `clear` is called.
+    pub(crate) fn read_all(&self) -> io::Result<Vec<Publish>> {
+        Ok(latest_retained(self.read()?))
+    }
+
+    /// Remove all messages from the spool, once they have been sent.
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn read(&self) -> io::Result<Vec<Publish>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        Ok(contents
+            .lines()
+            .filter_map(|line| {
+                let publish = decode(line);
+                if publish.is_none() {
+                    // Most likely the process stopped part way through writing it.
+                    log::warn!("Ignoring invalid spool record {:?}", line);
+                }
+                publish
+            })
+            .collect())
+    }
+
+    /// Rewrite the spool so that there is room for a record of the given length, dropping
+    /// superseded retained messages and then the oldest messages until it is at most half full.
+    fn compact(&mut self, needed: u64) -> io::Result<()> {
+        let mut records: VecDeque<Vec<u8>> =
+            latest_retained(self.read()?).iter().map(encode).collect();
+        let mut size: u64 = records.iter().map(|record| record.len() as u64).sum();
+        let mut dropped = 0;
+        while size + needed > self.max_size / 2 {
+            match records.pop_front() {
+                Some(record) => {
+                    size -= record.len() as u64;
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+        if dropped > 0 {
+            log::warn!(
+                "Spool {} is full, dropped {} oldest messages",
+                self.path.display(),
+                dropped
+            );
+        }
+        let mut file = File::create(&self.path)?;
+        for record in &records {
+            file.write_all(record)?;
+        }
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// Truncate the file at the given path after its last complete record, in case the process stopped
+/// part way through writing one, so that the next record isn't appended to it. Returns the
+/// resulting size of the file.
+fn discard_partial_record(path: &Path) -> io::Result<u64> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let size = contents
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |index| index + 1) as u64;
+    if size < contents.len() as u64 {
+        log::warn!("Discarding partial record from spool {}", path.display());
+        OpenOptions::new().write(true).open(path)?.set_len(size)?;
+    }
+    Ok(size)
+}
+
+/// Remove all but the last retained message for each topic, keeping everything else in order.
+fn latest_retained(publishes: Vec<Publish>) -> Vec<Publish> {
+    let mut seen = HashSet::new();
+    let mut latest: Vec<Publish> = publishes
+        .into_iter()
+        .rev()
+        .filter(|publish| !publish.retain || seen.insert(publish.topic.clone()))
+        .collect();
+    latest.reverse();
+    latest
+}
+
+fn encode(publish: &Publish) -> Vec<u8> {
+    let record = json!({
+        "topic": publish.topic,
+        "qos": publish.qos as u8,
+        "retain": publish.retain,
+        // Homie payloads are always UTF-8.
+        "payload": String::from_utf8_lossy(&publish.payload),
+    });
+    let mut record = record.to_string().into_bytes();
+    record.push(b'\n');
+    record
+}
+
+fn decode(line: &str) -> Option<Publish> {
+    let record: serde_json::Value = serde_json::from_str(line).ok()?;
+    let qos = match record["qos"].as_u64()? {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => return None,
+    };
+    let mut publish = Publish::new(record["topic"].as_str()?, qos, record["payload"].as_str()?);
+    publish.set_retain(record["retain"].as_bool()?);
+    Some(publish)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn spool_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("homie-spool-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn publish(topic: &str, payload: &str, retain: bool) -> Publish {
+        let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload);
+        publish.set_retain(retain);
+        publish
+    }
+
+    fn payloads(publishes: &[Publish]) -> Vec<String> {
+        publishes
+            .iter()
+            .map(|publish| String::from_utf8(publish.payload.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replays_in_order_with_latest_retained() -> io::Result<()> {
+        let path = spool_path("replay");
+        let mut spool = Spool::new(path.clone(), 10_000);
+        spool.append(&publish("a", "1", true))?;
+        spool.append(&publish("b", "2", false))?;
+        spool.append(&publish("a", "3", true))?;
+        spool.append(&publish("b", "4", false))?;
+
+        // The messages should survive a restart.
+        let mut spool = Spool::new(path.clone(), 10_000);
+        assert!(!spool.is_empty());
+        assert_eq!(payloads(&spool.read_all()?), vec!["2", "3", "4"]);
+        // Nothing is removed until the spool is cleared.
+        assert_eq!(payloads(&spool.read_all()?), vec!["2", "3", "4"]);
+        spool.clear()?;
+        assert!(spool.is_empty());
+        assert!(spool.read_all()?.is_empty());
+
+        fs::remove_file(path)
+    }
+
+    #[test]
+    fn drops_oldest_when_full() -> io::Result<()> {
+        let path = spool_path("full");
+        let record_length = encode(&publish("a", "0", false)).len() as u64;
+        let mut spool = Spool::new(path.clone(), record_length * 3);
+        spool.append(&publish("r", "0", true))?;
+        for i in 1..4 {
+            spool.append(&publish("a", &i.to_string(), false))?;
+        }
+        // Making room for the fourth record should have dropped everything down to half full.
+        assert_eq!(fs::metadata(&path)?.len(), record_length);
+        spool.append(&publish("a", "4", false))?;
+        spool.append(&publish("r", "5", true))?;
+        assert!(fs::metadata(&path)?.len() <= record_length * 3);
+
+        assert_eq!(payloads(&spool.read_all()?), vec!["3", "4", "5"]);
+
+        fs::remove_file(path)
+    }
+
+    #[test]
+    fn ignores_invalid_records() -> io::Result<()> {
+        let path = spool_path("invalid");
+        let mut spool = Spool::new(path.clone(), 10_000);
+        spool.append(&publish("a", "1", false))?;
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"topic\":\"b\",\"qos\":1,\"ret")?;
+
+        let spool = Spool::new(path.clone(), 10_000);
+        assert_eq!(payloads(&spool.read_all()?), vec!["1"]);
+
+        fs::remove_file(path)
+    }
+
+    #[test]
+    fn appends_after_partial_record() -> io::Result<()> {
+        let path = spool_path("partial");
+        let mut spool = Spool::new(path.clone(), 10_000);
+        spool.append(&publish("a", "1", false))?;
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"topic\":\"b\",\"qos\":1,\"ret")?;
+
+        // After a restart, the next record shouldn't be lost by being appended to the partial one.
+        let mut spool = Spool::new(path.clone(), 10_000);
+        spool.append(&publish("c", "2", false))?;
+        assert_eq!(payloads(&spool.read_all()?), vec!["1", "2"]);
+        assert_eq!(fs::metadata(&path)?.len(), spool.size);
+
+        fs::remove_file(path)
+    }
+}
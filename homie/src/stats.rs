@@ -0,0 +1,361 @@
+//! The [legacy stats](https://homieiot.github.io/extensions/) extension, including collecting
+//! system stats on Linux.
+
+use crate::{ignore_timeout, DevicePublisher, Error, HomieVersion};
+use futures::future::{self, FutureExt};
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::future::Future;
+use std::iter;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::task::{self, JoinHandle};
+use tokio::time::delay_for;
+
+pub(crate) type StatsProvider = Box<dyn Fn() -> Option<String> + Send + Sync>;
+
+/// Legacy stats extension.
+pub(crate) struct HomieStats {
+    publisher: DevicePublisher,
+    homie_version: HomieVersion,
+    start_time: Instant,
+    interval: Duration,
+    providers: Vec<(String, StatsProvider)>,
+    system_stats: SystemStats,
+    /// The names of the stats which were last published, for the `$stats` attribute in Homie 3.
+    published_names: Option<String>,
+}
+
+impl Debug for HomieStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HomieStats")
+            .field("publisher", &self.publisher)
+            .field("homie_version", &self.homie_version)
+            .field("start_time", &self.start_time)
+            .field("interval", &self.interval)
+            .field(
+                "providers",
+                &self
+                    .providers
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("system_stats", &self.system_stats)
+            .field("published_names", &self.published_names)
+            .finish()
+    }
+}
+
+impl HomieStats {
+    pub const EXTENSION_ID: &'static str = "org.homie.legacy-stats:0.1.1:[4.x]";
+
+    pub fn new(
+        publisher: DevicePublisher,
+        homie_version: HomieVersion,
+        interval: Duration,
+        providers: Vec<(String, StatsProvider)>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            publisher,
+            homie_version,
+            start_time: now,
+            interval,
+            providers,
+            system_stats: SystemStats::default(),
+            published_names: None,
+        }
+    }
+
+    /// Send initial topics.
+    pub async fn start(&self) -> Result<(), Error> {
+        // Homie 5 devices don't implement the legacy stats extension.
+        if self.homie_version == HomieVersion::V5 {
+            return Ok(());
+        }
+        self.publisher
+            .publish_retained("$stats/interval", self.interval.as_secs().to_string())
+            .await
+    }
+
+    /// Periodically send stats.
+    pub fn spawn(
+        mut self,
+    ) -> impl Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>> {
+        if self.homie_version == HomieVersion::V5 {
+            return future::pending().left_future();
+        }
+        let task: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
+            task::spawn(async move {
+                loop {
+                    ignore_timeout(self.publish_stats().await)?;
+                    delay_for(self.interval).await;
+                }
+            });
+        task.map(|res| Ok(res??)).right_future()
+    }
+
+    async fn publish_stats(&mut self) -> Result<(), Error> {
+        let uptime = Instant::now() - self.start_time;
+        self.publisher
+            .publish_retained("$stats/uptime", uptime.as_secs().to_string())
+            .await?;
+
+        let mut stats = self.system_stats.collect();
+        // Custom providers take precedence over the system stats.
+        stats.retain(|(name, _)| !self.providers.iter().any(|(custom, _)| custom == name));
+        stats.extend(
+            self.providers
+                .iter()
+                .filter_map(|(name, provider)| Some((name.clone(), provider()?))),
+        );
+        // Homie 3 requires a list of the stats which are published.
+        if self.homie_version == HomieVersion::V3 {
+            let names = iter::once("uptime")
+                .chain(stats.iter().map(|(name, _)| name.as_str()))
+                .collect::<Vec<_>>()
+                .join(",");
+            if self.published_names.as_ref() != Some(&names) {
+                self.publisher
+                    .publish_retained("$stats", names.as_str())
+                    .await?;
+                self.published_names = Some(names);
+            }
+        }
+        for (name, value) in stats {
+            self.publisher
+                .publish_retained(&format!("$stats/{}", name), value)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the stats from the legacy stats extension which we know how to find on Linux. On other
+/// platforms, or if the relevant files don't exist, they are left out.
+#[derive(Debug, Default)]
+struct SystemStats {
+    /// The CPU times from the last time we collected stats, to calculate the load since then.
+    previous_cpu_times: Option<CpuTimes>,
+}
+
+impl SystemStats {
+    fn collect(&mut self) -> Vec<(String, String)> {
+        let mut stats = vec![];
+        if let Some(signal) = read_to_string("/proc/net/wireless").and_then(|s| parse_signal(&s)) {
+            stats.push(("signal".to_string(), signal.to_string()));
+        }
+        if let Some(cputemp) = read_cpu_temperature() {
+            stats.push(("cputemp".to_string(), format!("{:.1}", cputemp)));
+        }
+        let cpu_times = read_to_string("/proc/stat").and_then(|s| parse_cpu_times(&s));
+        if let (Some(previous), Some(current)) = (self.previous_cpu_times, cpu_times) {
+            if let Some(cpuload) = current.load_since(&previous) {
+                stats.push(("cpuload".to_string(), cpuload.to_string()));
+            }
+        }
+        self.previous_cpu_times = cpu_times;
+        if let Some(battery) = read_power_supply("capacity") {
+            stats.push(("battery".to_string(), battery.to_string()));
+        }
+        if let Some(freeheap) = read_to_string("/proc/meminfo").and_then(|s| parse_meminfo(&s)) {
+            stats.push(("freeheap".to_string(), freeheap.to_string()));
+        }
+        if let Some(microvolts) = read_power_supply("voltage_now") {
+            stats.push((
+                "supply".to_string(),
+                format!("{:.2}", microvolts as f64 / 1_000_000.0),
+            ));
+        }
+        stats
+    }
+}
+
+fn read_to_string(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+/// Read the highest temperature of any thermal zone, in ºC.
+fn read_cpu_temperature() -> Option<f64> {
+    fs::read_dir("/sys/class/thermal")
+        .ok()?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if !path.file_name()?.to_str()?.starts_with("thermal_zone") {
+                return None;
+            }
+            let millidegrees: i64 = read_to_string(path.join("temp"))?.trim().parse().ok()?;
+            Some(millidegrees as f64 / 1000.0)
+        })
+        .fold(None, |max: Option<f64>, temperature| {
+            Some(max.map_or(temperature, |max| max.max(temperature)))
+        })
+}
+
+/// Read the given attribute of the first power supply which has it.
+fn read_power_supply(attribute: &str) -> Option<u64> {
+    let mut supplies = fs::read_dir("/sys/class/power_supply")
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect::<Vec<_>>();
+    supplies.sort();
+    supplies
+        .iter()
+        .find_map(|supply| read_to_string(supply.join(attribute))?.trim().parse().ok())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct CpuTimes {
+    idle: u64,
+    total: u64,
+}
+
+impl CpuTimes {
+    /// The percentage of time which the CPU has been busy since the given earlier times.
+    fn load_since(&self, previous: &CpuTimes) -> Option<u64> {
+        let total = self.total.checked_sub(previous.total)?;
+        let idle = self.idle.checked_sub(previous.idle)?;
+        if total == 0 {
+            return None;
+        }
+        Some((total - idle.min(total)) * 100 / total)
+    }
+}
+
+/// Parse the aggregate CPU line from `/proc/stat`.
+fn parse_cpu_times(stat: &str) -> Option<CpuTimes> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let times = line
+        .split_whitespace()
+        .skip(1)
+        .map(|time| time.parse())
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()?;
+    // The fourth and fifth fields are idle and iowait.
+    let idle = times.get(3)? + times.get(4).unwrap_or(&0);
+    Some(CpuTimes {
+        idle,
+        // Only the first eight fields (user to steal), as guest and guest_nice are already
+        // counted in user and nice.
+        total: times.iter().take(8).sum(),
+    })
+}
+
+/// Parse the available memory in bytes from `/proc/meminfo`.
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Parse the signal strength of the first wireless interface from `/proc/net/wireless`, as a
+/// percentage.
+fn parse_signal(wireless: &str) -> Option<u8> {
+    // There are two header lines before the interfaces.
+    let line = wireless.lines().nth(2)?;
+    let level: f64 = line
+        .split_whitespace()
+        .nth(3)?
+        .trim_end_matches('.')
+        .parse()
+        .ok()?;
+    // Map -100 dBm to 0% and -50 dBm to 100%. Negative values saturate to 0 when cast.
+    Some((2.0 * (level + 100.0)).min(100.0) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::Request;
+
+    #[tokio::test]
+    async fn homie_3_lists_published_stats() -> Result<(), Error> {
+        let (requests_tx, requests_rx) = async_channel::unbounded();
+        let publisher = DevicePublisher::new(requests_tx, "homie/test-device".to_string());
+        let mut stats = HomieStats::new(
+            publisher,
+            HomieVersion::V3,
+            Duration::from_secs(60),
+            vec![("custom".to_string(), Box::new(|| Some("42".to_string())))],
+        );
+
+        stats.publish_stats().await?;
+
+        let mut published = vec![];
+        while let Ok(Request::Publish(publish)) = requests_rx.try_recv() {
+            published.push((publish.topic, publish.payload.to_vec()));
+        }
+        let (_, names) = published
+            .iter()
+            .find(|(topic, _)| topic == "homie/test-device/$stats")
+            .unwrap();
+        let names = String::from_utf8(names.clone()).unwrap();
+        assert!(names.starts_with("uptime,"));
+        assert!(names.ends_with(",custom"));
+        Ok(())
+    }
+
+    #[test]
+    fn cpu_load_from_proc_stat() {
+        let before =
+            parse_cpu_times("cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 100 0 100 700 100 0 0 0 0 0\n")
+                .unwrap();
+        let after = parse_cpu_times("cpu  200 0 200 1200 200 0 0 0 0 0\n").unwrap();
+
+        assert_eq!(
+            before,
+            CpuTimes {
+                idle: 800,
+                total: 1000
+            }
+        );
+        assert_eq!(after.load_since(&before), Some(25));
+        assert_eq!(before.load_since(&before), None);
+    }
+
+    #[test]
+    fn cpu_load_ignores_guest_time() {
+        let before = parse_cpu_times(
+            "cpu  100 50 100 700 50 0 0 0 80 40
+",
+        )
+        .unwrap();
+        let after = parse_cpu_times(
+            "cpu  300 100 100 1200 50 0 0 0 280 90
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            before,
+            CpuTimes {
+                idle: 750,
+                total: 1000
+            }
+        );
+        assert_eq!(after.load_since(&before), Some(33));
+    }
+
+    #[test]
+    fn free_memory_from_meminfo() {
+        let meminfo = "MemTotal:        3906280 kB\nMemFree:          150000 kB\nMemAvailable:    2000000 kB\n";
+
+        assert_eq!(parse_meminfo(meminfo), Some(2_048_000_000));
+        assert_eq!(parse_meminfo("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn signal_from_proc_net_wireless() {
+        let wireless =
+            "Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+ wlan0: 0000   56.  -54.  -256        0      0      0      0     32        0
+";
+
+        assert_eq!(parse_signal(wireless), Some(92));
+        assert_eq!(parse_signal(&wireless[..100]), None);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::Datatype;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A colour value for a property with the `Color` datatype.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Color {
+    /// Red, green and blue components, each from 0 to 255.
+    Rgb(u8, u8, u8),
+    /// Hue from 0 to 360, and saturation and value from 0 to 100.
+    Hsv(u16, u8, u8),
+}
+
+impl Color {
+    fn format(&self) -> &'static str {
+        match self {
+            Self::Rgb(..) => "rgb",
+            Self::Hsv(..) => "hsv",
+        }
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rgb(red, green, blue) => write!(f, "{},{},{}", red, green, blue),
+            Self::Hsv(hue, saturation, value) => write!(f, "{},{},{}", hue, saturation, value),
+        }
+    }
+}
+
+/// The value of a Homie property.
+///
+/// The `Display` implementation gives the
+/// [payload format](https://homieiot.github.io/specification/#payload) used by Homie.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+    Enum(String),
+    Color(Color),
+}
+
+impl Value {
+    /// Parse a payload received from the MQTT broker as a value of the given datatype, and check
+    /// that it is valid for the given format.
+    pub(crate) fn parse(
+        payload: &str,
+        datatype: Datatype,
+        format: Option<&str>,
+    ) -> Result<Value, String> {
+        let value = match datatype {
+            Datatype::Integer => Value::Integer(parse_number(payload)?),
+            Datatype::Float => Value::Float(parse_number(payload)?),
+            Datatype::Boolean => match payload {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                _ => return Err(format!("{:?} is not a boolean", payload)),
+            },
+            Datatype::String => Value::String(payload.to_owned()),
+            Datatype::Enum => Value::Enum(payload.to_owned()),
+            Datatype::Color => {
+                let components = payload
+                    .split(',')
+                    .map(parse_number)
+                    .collect::<Result<Vec<u16>, String>>()?;
+                let (a, b, c) = match components.as_slice() {
+                    [a, b, c] => (*a, *b, *c),
+                    _ => return Err(format!("{:?} doesn't have 3 components", payload)),
+                };
+                let small = |component: u16| {
+                    if component > u8::MAX.into() {
+                        Err(format!("Colour component {} is too large", component))
+                    } else {
+                        Ok(component as u8)
+                    }
+                };
+                match format {
+                    Some("rgb") => Value::Color(Color::Rgb(small(a)?, small(b)?, small(c)?)),
+                    Some("hsv") => Value::Color(Color::Hsv(a, small(b)?, small(c)?)),
+                    _ => return Err(format!("Unsupported colour format {:?}", format)),
+                }
+            }
+        };
+        value.validate(datatype, format)?;
+        Ok(value)
+    }
+
+    /// Check that the value is of the given datatype, and within the range or set of values
+    /// allowed by the given format.
+    pub(crate) fn validate(&self, datatype: Datatype, format: Option<&str>) -> Result<(), String> {
+        match (self, datatype) {
+            (Value::Integer(value), Datatype::Integer) => check_range(*value, format),
+            // An integer is also a valid float payload.
+            (Value::Integer(value), Datatype::Float) => check_range(*value as f64, format),
+            (Value::Float(value), Datatype::Float) => {
+                if value.is_finite() {
+                    check_range(*value, format)
+                } else {
+                    Err(format!("{} is not a finite number", value))
+                }
+            }
+            (Value::Boolean(_), Datatype::Boolean) => Ok(()),
+            (Value::String(_), Datatype::String) => Ok(()),
+            (Value::Enum(value), Datatype::Enum) | (Value::String(value), Datatype::Enum) => {
+                let format = format.ok_or("Enum property has no format")?;
+                if format.split(',').any(|option| option == value) {
+                    Ok(())
+                } else {
+                    Err(format!("{:?} is not one of {:?}", value, format))
+                }
+            }
+            (Value::Color(color), Datatype::Color) => {
+                let format = format.ok_or("Colour property has no format")?;
+                if !format.split(',').any(|option| option == color.format()) {
+                    return Err(format!(
+                        "{} colour doesn't match format {:?}",
+                        color.format(),
+                        format
+                    ));
+                }
+                match color {
+                    Color::Hsv(hue, saturation, value)
+                        if *hue > 360 || *saturation > 100 || *value > 100 =>
+                    {
+                        Err(format!("HSV colour {} is out of range", color))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            (value, datatype) => Err(format!("{:?} is not of type {:?}", value, datatype)),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Integer(value) => value.fmt(f),
+            Self::Float(value) => value.fmt(f),
+            Self::Boolean(value) => value.fmt(f),
+            Self::String(value) | Self::Enum(value) => f.write_str(value),
+            Self::Color(color) => color.fmt(f),
+        }
+    }
+}
+
+macro_rules! impl_from_integer {
+    ($($type:ty),*) => {
+        $(
+            impl From<$type> for Value {
+                fn from(value: $type) -> Self {
+                    Value::Integer(value.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_integer!(i8, i16, i32, i64, u8, u16, u32);
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        // Go via the shortest decimal representation of the f32, so that e.g. 21.45 isn't
+        // published as 21.450000762939453.
+        Value::Float(value.to_string().parse().unwrap())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Boolean(value)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_owned())
+    }
+}
+
+impl From<Color> for Value {
+    fn from(value: Color) -> Self {
+        Value::Color(value)
+    }
+}
+
+fn parse_number<T: FromStr>(payload: &str) -> Result<T, String> {
+    payload
+        .parse()
+        .map_err(|_| format!("{:?} is not a valid number", payload))
+}
+
+/// Check that the given number is within the range given by a format like "0:100". Either end of
+/// the range may be omitted.
+fn check_range<T: FromStr + PartialOrd + Display>(
+    value: T,
+    format: Option<&str>,
+) -> Result<(), String> {
+    if let Some(format) = format {
+        let (min, max) = match format.splitn(2, ':').collect::<Vec<_>>().as_slice() {
+            [min, max] => (*min, *max),
+            _ => return Err(format!("Invalid range format {:?}", format)),
+        };
+        if !min.is_empty() && value < parse_number(min)? {
+            return Err(format!("{} is less than minimum {}", value, min));
+        }
+        if !max.is_empty() && value > parse_number(max)? {
+            return Err(format!("{} is greater than maximum {}", value, max));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_formatted_for_homie() {
+        assert_eq!(Value::from(42).to_string(), "42");
+        assert_eq!(Value::from(-1.5).to_string(), "-1.5");
+        assert_eq!(Value::from(21.45f32).to_string(), "21.45");
+        assert_eq!(Value::from(true).to_string(), "true");
+        assert_eq!(Value::Enum("high".to_string()).to_string(), "high");
+        assert_eq!(Value::from(Color::Rgb(255, 0, 10)).to_string(), "255,0,10");
+        assert_eq!(
+            Value::from(Color::Hsv(300, 50, 75)).to_string(),
+            "300,50,75"
+        );
+    }
+
+    #[test]
+    fn integer_range_is_checked() {
+        assert_eq!(
+            Value::from(50).validate(Datatype::Integer, Some("0:100")),
+            Ok(())
+        );
+        assert_eq!(
+            Value::from(7).validate(Datatype::Integer, Some(":10")),
+            Ok(())
+        );
+        assert!(Value::from(101)
+            .validate(Datatype::Integer, Some("0:100"))
+            .is_err());
+        assert!(Value::from(-1)
+            .validate(Datatype::Integer, Some("0:"))
+            .is_err());
+        assert!(Value::from(1.5).validate(Datatype::Integer, None).is_err());
+    }
+
+    #[test]
+    fn float_must_be_finite() {
+        assert_eq!(
+            Value::from(1).validate(Datatype::Float, Some("0:1.5")),
+            Ok(())
+        );
+        assert!(Value::from(f64::NAN)
+            .validate(Datatype::Float, None)
+            .is_err());
+    }
+
+    #[test]
+    fn enum_must_be_in_format() {
+        assert_eq!(
+            Value::from("low").validate(Datatype::Enum, Some("low,medium,high")),
+            Ok(())
+        );
+        assert!(Value::Enum("off".to_string())
+            .validate(Datatype::Enum, Some("low,medium,high"))
+            .is_err());
+        assert!(Value::from("low").validate(Datatype::Enum, None).is_err());
+    }
+
+    #[test]
+    fn color_must_match_format() {
+        assert_eq!(
+            Value::from(Color::Rgb(1, 2, 3)).validate(Datatype::Color, Some("rgb")),
+            Ok(())
+        );
+        assert!(Value::from(Color::Rgb(1, 2, 3))
+            .validate(Datatype::Color, Some("hsv"))
+            .is_err());
+        assert!(Value::from(Color::Hsv(361, 2, 3))
+            .validate(Datatype::Color, Some("hsv"))
+            .is_err());
+    }
+
+    #[test]
+    fn parse_valid_payloads() {
+        assert_eq!(
+            Value::parse("12", Datatype::Integer, Some("0:20")),
+            Ok(Value::Integer(12))
+        );
+        assert_eq!(
+            Value::parse("12.5", Datatype::Float, None),
+            Ok(Value::Float(12.5))
+        );
+        assert_eq!(
+            Value::parse("false", Datatype::Boolean, None),
+            Ok(Value::Boolean(false))
+        );
+        assert_eq!(
+            Value::parse("medium", Datatype::Enum, Some("low,medium,high")),
+            Ok(Value::Enum("medium".to_string()))
+        );
+        assert_eq!(
+            Value::parse("10,20,30", Datatype::Color, Some("rgb")),
+            Ok(Value::Color(Color::Rgb(10, 20, 30)))
+        );
+        assert_eq!(
+            Value::parse("300,20,30", Datatype::Color, Some("hsv")),
+            Ok(Value::Color(Color::Hsv(300, 20, 30)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_payloads() {
+        assert!(Value::parse("1.5", Datatype::Integer, None).is_err());
+        assert!(Value::parse("30", Datatype::Integer, Some("0:20")).is_err());
+        assert!(Value::parse("yes", Datatype::Boolean, None).is_err());
+        assert!(Value::parse("off", Datatype::Enum, Some("low,high")).is_err());
+        assert!(Value::parse("300,20,30", Datatype::Color, Some("rgb")).is_err());
+        assert!(Value::parse("1,2", Datatype::Color, Some("rgb")).is_err());
+    }
+}
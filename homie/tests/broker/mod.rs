@@ -0,0 +1,380 @@
+//! A minimal in-process MQTT 3.1.1 broker, for testing devices and controllers end to end over a
+//! real TCP connection.
+//!
+//! It supports just enough of the protocol for rumqttc: retained messages, wildcard subscriptions,
+//! last wills, and QoS 0 and 1 from clients. Messages are always delivered to subscribers with QoS
+//! 0.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, ReadHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tokio::time::{delay_for, timeout};
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// How long `wait_for` waits before giving up.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+#[derive(Debug)]
+struct Client {
+    id: usize,
+    subscriptions: Vec<String>,
+    packets_tx: async_channel::Sender<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    retained: BTreeMap<String, Vec<u8>>,
+    clients: Vec<Client>,
+    next_client_id: usize,
+}
+
+impl State {
+    /// Store the message if it is retained, and send it to all clients with matching
+    /// subscriptions.
+    fn route(&mut self, message: Message) {
+        if message.retain {
+            if message.payload.is_empty() {
+                self.retained.remove(&message.topic);
+            } else {
+                self.retained
+                    .insert(message.topic.clone(), message.payload.clone());
+            }
+        }
+        // The retain flag is only set for messages which were retained before subscribing.
+        let packet = encode_publish(&message.topic, &message.payload, false);
+        for client in &self.clients {
+            if client
+                .subscriptions
+                .iter()
+                .any(|filter| topic_matches(filter, &message.topic))
+            {
+                let _ = client.packets_tx.try_send(packet.clone());
+            }
+        }
+    }
+
+    fn client(&mut self, id: usize) -> Option<&mut Client> {
+        self.clients.iter_mut().find(|client| client.id == id)
+    }
+}
+
+/// An MQTT broker listening on a random port on localhost.
+#[derive(Clone, Debug)]
+pub struct Broker {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+impl Broker {
+    /// Start listening, and spawn a task to accept connections.
+    pub async fn start() -> Broker {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = Broker {
+            port,
+            state: Default::default(),
+        };
+        let state = broker.state.clone();
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                task::spawn(handle_connection(state.clone(), stream));
+            }
+        });
+        broker
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Get all the retained messages which the broker currently has, by topic.
+    pub fn retained(&self) -> BTreeMap<String, String> {
+        self.state
+            .lock()
+            .unwrap()
+            .retained
+            .iter()
+            .map(|(topic, payload)| (topic.clone(), String::from_utf8_lossy(payload).into_owned()))
+            .collect()
+    }
+
+    /// Publish a message to all subscribed clients, as if it was sent by another client.
+    pub fn publish(&self, topic: &str, payload: &str) {
+        self.state.lock().unwrap().route(Message {
+            topic: topic.to_owned(),
+            payload: payload.as_bytes().to_vec(),
+            retain: false,
+        });
+    }
+
+    /// Wait until the given condition on the retained messages is true, or panic if it takes too
+    /// long.
+    pub async fn wait_for(&self, condition: impl Fn(&BTreeMap<String, String>) -> bool) {
+        let result = timeout(WAIT_TIMEOUT, async {
+            while !condition(&self.retained()) {
+                delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        if result.is_err() {
+            panic!("Timed out, retained messages: {:#?}", self.retained());
+        }
+    }
+
+    /// Wait until the retained message for the given topic has the given payload.
+    pub async fn wait_for_retained(&self, topic: &str, payload: &str) {
+        self.wait_for(|retained| retained.get(topic).map(String::as_str) == Some(payload))
+            .await
+    }
+}
+
+async fn handle_connection(state: Arc<Mutex<State>>, stream: TcpStream) {
+    let (mut reader, mut writer) = io::split(stream);
+    let (packets_tx, packets_rx) = async_channel::unbounded::<Vec<u8>>();
+    task::spawn(async move {
+        while let Ok(packet) = packets_rx.recv().await {
+            if writer.write_all(&packet).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let id = {
+        let mut state = state.lock().unwrap();
+        let id = state.next_client_id;
+        state.next_client_id += 1;
+        state.clients.push(Client {
+            id,
+            subscriptions: vec![],
+            packets_tx: packets_tx.clone(),
+        });
+        id
+    };
+
+    let mut will = None;
+    let mut clean_disconnect = false;
+    while let Ok((packet_type, flags, body)) = read_packet(&mut reader).await {
+        let mut body = Body(&body);
+        match packet_type {
+            CONNECT => {
+                will = parse_connect(&mut body);
+                let _ = packets_tx.send(vec![CONNACK << 4, 2, 0, 0]).await;
+            }
+            PUBLISH => {
+                let qos = (flags >> 1) & 0x03;
+                let topic = body.string();
+                let pkid = if qos > 0 { body.u16() } else { 0 };
+                let message = Message {
+                    topic,
+                    payload: body.rest().to_vec(),
+                    retain: flags & 0x01 != 0,
+                };
+                state.lock().unwrap().route(message);
+                match qos {
+                    1 => send_ack(&packets_tx, PUBACK << 4, pkid).await,
+                    2 => send_ack(&packets_tx, PUBREC << 4, pkid).await,
+                    _ => {}
+                }
+            }
+            PUBREL => send_ack(&packets_tx, PUBCOMP << 4, body.u16()).await,
+            SUBSCRIBE => {
+                let pkid = body.u16();
+                let mut filters = vec![];
+                while !body.0.is_empty() {
+                    filters.push(body.string());
+                    body.u8();
+                }
+                // Grant QoS 0 for everything.
+                let mut suback = vec![SUBACK << 4, 2 + filters.len() as u8];
+                suback.extend_from_slice(&pkid.to_be_bytes());
+                suback.extend(filters.iter().map(|_| 0));
+                let _ = packets_tx.send(suback).await;
+                let retained = {
+                    let mut state = state.lock().unwrap();
+                    if let Some(client) = state.client(id) {
+                        client.subscriptions.extend(filters.iter().cloned());
+                    }
+                    state
+                        .retained
+                        .iter()
+                        .filter(|(topic, _)| filters.iter().any(|f| topic_matches(f, topic)))
+                        .map(|(topic, payload)| encode_publish(topic, payload, true))
+                        .collect::<Vec<_>>()
+                };
+                for packet in retained {
+                    let _ = packets_tx.send(packet).await;
+                }
+            }
+            UNSUBSCRIBE => {
+                let pkid = body.u16();
+                let mut filters = vec![];
+                while !body.0.is_empty() {
+                    filters.push(body.string());
+                }
+                if let Some(client) = state.lock().unwrap().client(id) {
+                    client
+                        .subscriptions
+                        .retain(|filter| !filters.contains(filter));
+                }
+                send_ack(&packets_tx, UNSUBACK << 4, pkid).await;
+            }
+            PINGREQ => {
+                let _ = packets_tx.send(vec![PINGRESP << 4, 0]).await;
+            }
+            DISCONNECT => {
+                clean_disconnect = true;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let mut state = state.lock().unwrap();
+    state.clients.retain(|client| client.id != id);
+    if !clean_disconnect {
+        if let Some(will) = will {
+            state.route(will);
+        }
+    }
+}
+
+async fn send_ack(packets_tx: &async_channel::Sender<Vec<u8>>, header: u8, pkid: u16) {
+    let mut packet = vec![header, 2];
+    packet.extend_from_slice(&pkid.to_be_bytes());
+    let _ = packets_tx.send(packet).await;
+}
+
+/// Parse the body of a CONNECT packet, and return the last will if there is one.
+fn parse_connect(body: &mut Body) -> Option<Message> {
+    let _protocol_name = body.string();
+    let _protocol_level = body.u8();
+    let flags = body.u8();
+    let _keep_alive = body.u16();
+    let _client_id = body.string();
+    if flags & 0x04 == 0 {
+        return None;
+    }
+    let topic = body.string();
+    let payload = body.bytes().to_vec();
+    Some(Message {
+        topic,
+        payload,
+        retain: flags & 0x20 != 0,
+    })
+}
+
+/// Read a packet, and return its type, flags and body.
+async fn read_packet(reader: &mut ReadHalf<TcpStream>) -> io::Result<(u8, u8, Vec<u8>)> {
+    let header = reader.read_u8().await?;
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_u8().await?;
+        length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok((header >> 4, header & 0x0f, body))
+}
+
+fn encode_publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+
+    let mut packet = vec![PUBLISH << 4 | retain as u8];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length & 0x7f) as u8;
+        length >>= 7;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+/// Whether the given topic matches the given subscription filter, which may contain wildcards.
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// A cursor for reading fields from the body of a packet.
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn u8(&mut self) -> u8 {
+        let value = self.0[0];
+        self.0 = &self.0[1..];
+        value
+    }
+
+    fn u16(&mut self) -> u16 {
+        let value = u16::from_be_bytes([self.0[0], self.0[1]]);
+        self.0 = &self.0[2..];
+        value
+    }
+
+    /// A length-prefixed byte array.
+    fn bytes(&mut self) -> &'a [u8] {
+        let length = self.u16() as usize;
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        value
+    }
+
+    /// A length-prefixed UTF-8 string.
+    fn string(&mut self) -> String {
+        String::from_utf8_lossy(self.bytes()).into_owned()
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.0;
+        self.0 = &[];
+        rest
+    }
+}
@@ -0,0 +1,93 @@
+//! Tests for a Homie device talking to a real MQTT broker over TCP.
+
+mod broker;
+
+use broker::Broker;
+use homie::{Datatype, HomieDevice, Node, Property, Value};
+use rumqttc::MqttOptions;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::{delay_for, timeout};
+
+#[tokio::test]
+async fn device_lifecycle_and_set() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let broker = Broker::start().await;
+    let mqttoptions = MqttOptions::new("homie_test_device", "127.0.0.1", broker.port());
+    let (mut homie, _homie_handle) =
+        HomieDevice::builder("homie/test-device", "Test device", mqttoptions)
+            .spawn()
+            .await?;
+    broker
+        .wait_for_retained("homie/test-device/$state", "init")
+        .await;
+
+    let (set_tx, set_rx) = async_channel::unbounded();
+    homie
+        .add_node(Node::new(
+            "light",
+            "Light",
+            "light",
+            vec![
+                Property::new("power", "On", Datatype::Boolean, true, None, None)?
+                    .with_set_handler(move |value| {
+                        let set_tx = set_tx.clone();
+                        async move {
+                            set_tx.send(value.clone()).await.unwrap();
+                            Ok(Some(value))
+                        }
+                    }),
+            ],
+        )?)
+        .await?;
+    homie.ready().await?;
+    broker
+        .wait_for_retained("homie/test-device/$state", "ready")
+        .await;
+
+    let retained = broker.retained();
+    for (topic, payload) in &[
+        ("homie/test-device/$homie", "4.0"),
+        ("homie/test-device/$name", "Test device"),
+        ("homie/test-device/$nodes", "light"),
+        ("homie/test-device/light/$name", "Light"),
+        ("homie/test-device/light/$properties", "power"),
+        ("homie/test-device/light/power/$datatype", "boolean"),
+        ("homie/test-device/light/power/$settable", "true"),
+    ] {
+        assert_eq!(retained.get(*topic).map(String::as_str), Some(*payload));
+    }
+    assert!(retained.contains_key("homie/test-device/$stats/interval"));
+    assert!(retained.contains_key("homie/test-device/$fw/version"));
+
+    // A set from a controller should be passed to the handler, and the result published.
+    broker.publish("homie/test-device/light/power/set", "true");
+    let value = timeout(Duration::from_secs(5), set_rx.recv()).await??;
+    assert_eq!(value, Value::Boolean(true));
+    broker
+        .wait_for_retained("homie/test-device/light/power", "true")
+        .await;
+
+    // Invalid values shouldn't get as far as the handler.
+    broker.publish("homie/test-device/light/power/set", "maybe");
+    homie.remove_node("light").await?;
+    broker
+        .wait_for(|retained| {
+            !retained
+                .keys()
+                .any(|topic| topic.starts_with("homie/test-device/light/"))
+        })
+        .await;
+    assert!(set_rx.is_empty());
+
+    homie.disconnect().await?;
+    broker
+        .wait_for_retained("homie/test-device/$state", "disconnected")
+        .await;
+    // The last will shouldn't be published after a clean disconnect.
+    delay_for(Duration::from_millis(100)).await;
+    assert_eq!(
+        broker.retained()["homie/test-device/$state"],
+        "disconnected"
+    );
+    Ok(())
+}
@@ -2,7 +2,7 @@ use bluez_generated::generated::OrgBluezGattCharacteristic1;
 use dbus::arg::RefArg;
 use dbus::nonblock::stdintf::org_freedesktop_dbus::ObjectManager;
 use itertools::Itertools;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::{Display, Formatter};
@@ -14,6 +14,8 @@ pub mod session;
 pub use session::{MijiaEvent, MijiaSession};
 
 const MIJIA_SERVICE_DATA_UUID: &str = "0000fe95-0000-1000-8000-00805f9b34fb";
+/// The Environmental Sensing service UUID, which the pvvx firmware uses for its advertisements.
+pub const PVVX_SERVICE_DATA_UUID: &str = "0000181a-0000-1000-8000-00805f9b34fb";
 const SENSOR_READING_CHARACTERISTIC_PATH: &str = "/service0021/char0035";
 const CONNECTION_INTERVAL_CHARACTERISTIC_PATH: &str = "/service0021/char0045";
 /// 500 in little-endian
@@ -144,6 +146,37 @@ pub fn decode_value(value: &[u8]) -> Option<Readings> {
     })
 }
 
+/// Encode the given readings as service data in the
+/// [custom format](https://github.com/pvvx/ATC_MiThermometer#custom-format-all-data-little-endian)
+/// used by the pvvx firmware, for advertising with `PVVX_SERVICE_DATA_UUID`.
+///
+/// Returns `None` if the MAC address is malformed.
+pub fn encode_pvvx_service_data(
+    mac_address: &str,
+    readings: &Readings,
+    counter: u8,
+) -> Option<Vec<u8>> {
+    let mut mac = mac_address
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if mac.len() != 6 {
+        return None;
+    }
+    // The MAC address is sent least significant byte first, like everything else.
+    mac.reverse();
+
+    let mut value = mac;
+    value.extend_from_slice(&((readings.temperature * 100.0).round() as i16).to_le_bytes());
+    value.extend_from_slice(&(readings.humidity as u16 * 100).to_le_bytes());
+    value.extend_from_slice(&readings.battery_voltage.to_le_bytes());
+    value.push(min(readings.battery_percent, 100) as u8);
+    value.push(counter);
+    // Flags, for the reed switch and GPIO trigger outputs which the Mijia sensors don't have.
+    value.push(0);
+    Some(value)
+}
+
 /// Read the given file of key-value pairs into a hashmap.
 /// Returns an empty hashmap if the file doesn't exist, or an error if it is malformed.
 pub fn hashmap_from_file(filename: &str) -> Result<HashMap<String, String>, io::Error> {
@@ -183,6 +216,32 @@ mod tests {
         assert_eq!(decode_value(&[1, 2, 3, 4, 5, 6]), None);
     }
 
+    #[test]
+    fn encode_pvvx_valid() {
+        let readings = Readings {
+            temperature: 21.45,
+            humidity: 56,
+            battery_voltage: 2950,
+            battery_percent: 85,
+        };
+        assert_eq!(
+            encode_pvvx_service_data("A4:C1:38:01:02:03", &readings, 7),
+            Some(vec![
+                0x03, 0x02, 0x01, 0x38, 0xC1, 0xA4, 0x61, 0x08, 0xE0, 0x15, 0x86, 0x0B, 85, 7, 0
+            ])
+        );
+    }
+
+    #[test]
+    fn encode_pvvx_invalid_mac() {
+        let readings = decode_value(&[1, 2, 3, 4, 10]).unwrap();
+        assert_eq!(encode_pvvx_service_data("A4:C1:38", &readings, 0), None);
+        assert_eq!(
+            encode_pvvx_service_data("A4:C1:38:01:02:XX", &readings, 0),
+            None
+        );
+    }
+
     #[test]
     fn decode_valid() {
         assert_eq!(
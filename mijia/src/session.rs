@@ -1,7 +1,8 @@
 use crate::{decode_value, Readings, DBUS_METHOD_CALL_TIMEOUT, SENSOR_READING_CHARACTERISTIC_PATH};
 use anyhow::Context;
 use bluez_generated::bluetooth_event::BluetoothEvent;
-use bluez_generated::generated::{OrgBluezAdapter1, OrgBluezDevice1};
+use bluez_generated::discovery_filter::{DiscoveryFilter, Transport};
+use bluez_generated::generated::{OrgBluezAdapter1, OrgBluezBattery1, OrgBluezDevice1};
 use core::fmt::Debug;
 use core::future::Future;
 use dbus::{
@@ -22,6 +23,10 @@ pub enum MijiaEvent {
     Disconnected {
         object_path: String,
     },
+    BatteryPercentage {
+        object_path: String,
+        percentage: u8,
+    },
 }
 
 impl MijiaEvent {
@@ -42,6 +47,13 @@ impl MijiaEvent {
                 object_path,
                 connected: false,
             }) => Some(MijiaEvent::Disconnected { object_path }),
+            Some(BluetoothEvent::BatteryPercentage {
+                object_path,
+                percentage,
+            }) => Some(MijiaEvent::BatteryPercentage {
+                object_path,
+                percentage,
+            }),
             _ => None,
         }
     }
@@ -84,22 +96,45 @@ impl MijiaSession {
         ))
     }
 
+    /// Power on the adapter and start discovery, with a filter suitable for finding Mijia
+    /// sensors.
     pub async fn start_discovery(&self) -> Result<(), anyhow::Error> {
-        let adapter = dbus::nonblock::Proxy::new(
-            "org.bluez",
-            "/org/bluez/hci0",
-            DBUS_METHOD_CALL_TIMEOUT,
-            self.connection.clone(),
-        );
+        self.start_discovery_with_filter(&sensor_discovery_filter())
+            .await
+    }
+
+    /// Power on the adapter and start discovery, only reporting devices which match the given
+    /// filter.
+    ///
+    /// It is fine to call this again while discovery is already running, e.g. to make sure that it
+    /// hasn't been stopped by something else.
+    pub async fn start_discovery_with_filter(
+        &self,
+        filter: &DiscoveryFilter,
+    ) -> Result<(), anyhow::Error> {
+        let adapter = self.adapter();
         adapter
             .set_powered(true)
             .await
             .with_context(|| std::line!().to_string())?;
         adapter
-            .start_discovery()
+            .set_discovery_filter(filter.to_properties())
             .await
-            .unwrap_or_else(|err| println!("starting discovery failed {:?}", err));
-        Ok(())
+            .with_context(|| std::line!().to_string())?;
+        match adapter.start_discovery().await {
+            // We already have a discovery session, so there's nothing more to do.
+            Err(e) if e.name() == Some("org.bluez.Error.InProgress") => Ok(()),
+            result => result.with_context(|| std::line!().to_string()),
+        }
+    }
+
+    /// Stop a discovery which was previously started with `start_discovery` or
+    /// `start_discovery_with_filter`.
+    pub async fn stop_discovery(&self) -> Result<(), anyhow::Error> {
+        self.adapter()
+            .stop_discovery()
+            .await
+            .with_context(|| std::line!().to_string())
     }
 
     /// Get a stream of reading/disconnected events for all sensors.
@@ -121,6 +156,15 @@ impl MijiaSession {
         ))
     }
 
+    fn adapter(&self) -> impl OrgBluezAdapter1 {
+        dbus::nonblock::Proxy::new(
+            "org.bluez",
+            "/org/bluez/hci0",
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection.clone(),
+        )
+    }
+
     fn device(&self, object_path: &str) -> impl OrgBluezDevice1 {
         dbus::nonblock::Proxy::new(
             "org.bluez",
@@ -143,4 +187,56 @@ impl MijiaSession {
             .await
             .with_context(|| std::line!().to_string())
     }
+
+    /// Read the battery level reported by the given device through the standard Battery Service,
+    /// as a percentage. This will fail if the device doesn't support it, or isn't connected.
+    ///
+    /// Changes are also reported as `MijiaEvent::BatteryPercentage` events.
+    pub async fn read_battery_percentage(&self, object_path: &str) -> Result<u8, anyhow::Error> {
+        let battery = dbus::nonblock::Proxy::new(
+            "org.bluez",
+            object_path.to_owned(),
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection.clone(),
+        );
+        battery
+            .percentage()
+            .await
+            .with_context(|| std::line!().to_string())
+    }
+
+    /// Pair with the given device. Some devices need to be paired before their protected
+    /// characteristics can be accessed.
+    ///
+    /// This needs a pairing agent to be registered (see `bluez_generated::agent::Agent`) unless
+    /// the device uses "just works" pairing.
+    pub async fn pair(&self, object_path: &str) -> Result<(), anyhow::Error> {
+        self.device(object_path)
+            .pair()
+            .await
+            .with_context(|| std::line!().to_string())
+    }
+
+    /// Mark the given device as trusted, so that BlueZ will allow it to reconnect without asking
+    /// the pairing agent for authorization.
+    pub async fn trust(&self, object_path: &str) -> Result<(), anyhow::Error> {
+        self.device(object_path)
+            .set_trusted(true)
+            .await
+            .with_context(|| std::line!().to_string())
+    }
+}
+
+/// The discovery filter used by `MijiaSession::start_discovery`.
+///
+/// We don't filter on `MIJIA_SERVICE_DATA_UUID`, because BlueZ only matches the UUID filter
+/// against advertised service UUIDs, and the sensors only include it as service data.
+fn sensor_discovery_filter() -> DiscoveryFilter {
+    DiscoveryFilter {
+        transport: Some(Transport::Le),
+        // We connect to sensors to get readings rather than reading their advertisements, so
+        // there's no point waking up for every advertisement they send.
+        duplicate_data: Some(false),
+        ..Default::default()
+    }
 }
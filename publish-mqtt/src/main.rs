@@ -1,7 +1,7 @@
 use anyhow::Context;
 use futures::stream::StreamExt;
 use futures::{FutureExt, TryFutureExt};
-use homie::{Datatype, HomieDevice, Node, Property};
+use homie::{Datatype, HomieDevice, Id, Node, Property};
 use mijia::{
     get_sensors, hashmap_from_file, start_notify_sensor, MijiaEvent, MijiaSession, Readings,
     SensorProps,
@@ -23,6 +23,7 @@ const SCAN_INTERVAL: Duration = Duration::from_secs(15);
 const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
 const UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
 const SENSOR_NAMES_FILENAME: &str = "sensor_names.conf";
+const DEFAULT_SPOOL_MAX_SIZE: u64 = 10 * 1024 * 1024;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -63,9 +64,20 @@ async fn main() -> Result<(), anyhow::Error> {
     let mqtt_prefix =
         std::env::var("MQTT_PREFIX").unwrap_or_else(|_| DEFAULT_MQTT_PREFIX.to_string());
     let device_base = format!("{}/{}", mqtt_prefix, device_id);
-    let (homie, homie_handle) = HomieDevice::builder(&device_base, &device_name, mqttoptions)
-        .spawn()
-        .await?;
+    let mut homie_builder = HomieDevice::builder(&device_base, &device_name, mqttoptions);
+    // Set this (usually to "homeassistant") to have sensors show up in Home Assistant automatically.
+    if let Ok(discovery_prefix) = std::env::var("HOMEASSISTANT_DISCOVERY_PREFIX") {
+        homie_builder.set_home_assistant_discovery(&discovery_prefix);
+    }
+    // Set this to keep readings in a file while the MQTT broker is unreachable.
+    if let Ok(spool_file) = std::env::var("SPOOL_FILE") {
+        let max_size = std::env::var("SPOOL_MAX_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_SPOOL_MAX_SIZE);
+        homie_builder.set_spool(spool_file, max_size);
+    }
+    let (homie, homie_handle) = homie_builder.spawn().await?;
 
     let local = task::LocalSet::new();
 
@@ -136,11 +148,20 @@ impl Sensor {
         }
     }
 
+    /// The Homie node ID for the sensor, which is its MAC address in lowercase without colons, e.g.
+    /// `a4c138123456`.
+    ///
+    /// Older versions used the MAC address as-is, e.g. `A4C138123456`, which isn't a valid Homie ID.
+    /// See the README for how to clean up the topics they left behind.
     pub fn node_id(&self) -> String {
-        self.mac_address.replace(":", "")
+        // MAC addresses are uppercase, but Homie IDs must be lowercase. If the MAC address is
+        // somehow empty then creating the node will fail with an invalid ID.
+        Id::sanitise(&self.mac_address.replace(":", ""))
+            .map(String::from)
+            .unwrap_or_default()
     }
 
-    fn as_node(&self) -> Node {
+    fn as_node(&self) -> Result<Node, homie::Error> {
         Node::new(
             &self.node_id(),
             &self.name,
@@ -153,7 +174,7 @@ impl Sensor {
                     false,
                     Some("ºC"),
                     None,
-                ),
+                )?,
                 Property::new(
                     Self::PROPERTY_ID_HUMIDITY,
                     "Humidity",
@@ -161,7 +182,7 @@ impl Sensor {
                     false,
                     Some("%"),
                     None,
-                ),
+                )?,
                 Property::new(
                     Self::PROPERTY_ID_BATTERY,
                     "Battery level",
@@ -169,7 +190,7 @@ impl Sensor {
                     false,
                     Some("%"),
                     None,
-                ),
+                )?,
             ],
         )
     }
@@ -187,7 +208,7 @@ impl Sensor {
             .publish_value(
                 &node_id,
                 Self::PROPERTY_ID_TEMPERATURE,
-                format!("{:.2}", readings.temperature),
+                readings.temperature,
             )
             .await
             .with_context(|| std::line!().to_string())?;
@@ -243,13 +264,29 @@ async fn bluetooth_connection_loop(
     sensor_names: &HashMap<String, String>,
 ) -> Result<(), anyhow::Error> {
     let mut next_scan_due = Instant::now();
+    let mut discovering = false;
     loop {
         let now = Instant::now();
-        if now > next_scan_due && state.lock().await.sensors_connected.len() < sensor_names.len() {
-            next_scan_due = now + SCAN_INTERVAL;
-            check_for_sensors(state.clone(), bt_session, &sensor_names)
-                .await
-                .with_context(|| std::line!().to_string())?;
+        if state.lock().await.sensors_connected.len() < sensor_names.len() {
+            if now > next_scan_due {
+                next_scan_due = now + SCAN_INTERVAL;
+                // Start discovery again on every scan, in case something else stopped it, e.g.
+                // bluetoothd being restarted. This does nothing if it's already running.
+                if let Err(e) = bt_session.start_discovery().await {
+                    println!("Starting discovery failed: {:?}", e);
+                }
+                discovering = true;
+                check_for_sensors(state.clone(), bt_session, &sensor_names)
+                    .await
+                    .with_context(|| std::line!().to_string())?;
+            }
+        } else if discovering {
+            println!("All sensors connected. Stopping discovery.");
+            // Failing to stop discovery isn't fatal, it just wastes some power.
+            if let Err(e) = bt_session.stop_discovery().await {
+                println!("Stopping discovery failed: {:?}", e);
+            }
+            discovering = false;
         }
 
         {
@@ -283,8 +320,6 @@ async fn check_for_sensors(
     bt_session: &MijiaSession,
     sensor_names: &HashMap<String, String>,
 ) -> Result<(), anyhow::Error> {
-    bt_session.start_discovery().await?;
-
     let sensors = get_sensors(bt_session)
         .await
         .with_context(|| std::line!().to_string())?;
@@ -343,7 +378,7 @@ async fn connect_start_sensor<'a>(
     match start_notify_sensor(bt_session, &sensor.object_path).await {
         Ok(()) => {
             homie
-                .add_node(sensor.as_node())
+                .add_node(sensor.as_node()?)
                 .await
                 .with_context(|| std::line!().to_string())?;
             sensor.connection_status = ConnectionStatus::Connected;
@@ -453,7 +488,7 @@ async fn handle_bluetooth_event(
                     object_path
                 );
                 homie
-                    .add_node(sensor.as_node())
+                    .add_node(sensor.as_node()?)
                     .await
                     .with_context(|| std::line!().to_string())?;
                 sensor.publish_readings(homie, &readings).await?;
@@ -478,6 +513,7 @@ async fn handle_bluetooth_event(
                 );
             }
         }
+        MijiaEvent::BatteryPercentage { .. } => {}
     };
 
     Ok(())